@@ -0,0 +1,84 @@
+use std::path::Path;
+
+use itertools::Itertools;
+
+use crate::capture::read_recording;
+use crate::decoder::{decode, State};
+use crate::key::{Key, KeyEstimator, KeyTransitions};
+use crate::model::{chord_name, Model};
+use crate::semi_markov::{decode_segments, Durations};
+
+/// A run of consecutive observations decoded as the same chord in the same key.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Segment {
+    pub(crate) start: usize,
+    pub(crate) end: usize,
+    pub(crate) state: State,
+}
+
+pub(crate) fn segments(states: &[State]) -> Vec<Segment> {
+    let mut segments: Vec<Segment> = vec![];
+    for (i, &state) in states.iter().enumerate() {
+        match segments.last_mut() {
+            Some(segment) if segment.state == state => segment.end = i + 1,
+            _ => segments.push(Segment {
+                start: i,
+                end: i + 1,
+                state,
+            }),
+        }
+    }
+    segments
+}
+
+/// Decodes a recording made with `--record` and prints its chord timeline, then the key regions
+/// found by `key_estimator`. With `durations`, the chords are redecoded with explicit durations
+/// under the decoded keys.
+pub(crate) fn analyze(
+    recording: &Path,
+    model: &Model,
+    key_change: f32,
+    durations: Option<&Durations>,
+    mut key_estimator: KeyEstimator,
+) {
+    let observations = read_recording(recording);
+    let mut states = decode(model, &observations, key_change);
+    if let Some(durations) = durations {
+        let keys = states.iter().map(|s| Key::from_index(s.key)).collect_vec();
+        let transitions = KeyTransitions::new(model);
+        let chords = decode_segments(model, &observations, &keys, durations, &transitions);
+        for (state, chord) in states.iter_mut().zip(chords) {
+            state.chord = chord;
+        }
+    }
+    println!("{:>6} {:>6}  chord  key", "start", "end");
+    for segment in segments(&states) {
+        println!(
+            "{:>6} {:>6}  {:<5}  {}",
+            segment.start,
+            segment.end,
+            chord_name(segment.state.chord),
+            chord_name(segment.state.key),
+        );
+    }
+
+    let changes = observations
+        .iter()
+        .filter_map(|observation| key_estimator.push(observation))
+        .collect_vec();
+    println!("\n{:>6} {:>6}  key region", "start", "end");
+    for region in key_estimator.regions() {
+        let modulation = changes
+            .iter()
+            .find(|change| change.beat == region.start)
+            .map_or(String::new(), |change| {
+                format!("  ({:?})", change.modulation)
+            });
+        println!(
+            "{:>6} {:>6}  {}{modulation}",
+            region.start,
+            region.end,
+            region.key.name(),
+        );
+    }
+}
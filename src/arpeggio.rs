@@ -0,0 +1,217 @@
+use std::time::{Duration, Instant};
+
+use chords::Chord;
+use clap::ValueEnum;
+use num::ToPrimitive;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Deserialize;
+
+use crate::tempo::Tempo;
+use crate::voice::{Output, Voices};
+
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Pattern {
+    Up,
+    Down,
+    /// Up then down, without repeating the ends.
+    UpDown,
+    Random,
+    /// The held keys in the order they were pressed, each moved to the nearest chord tone.
+    AsPlayed,
+}
+
+/// Arpeggiates the current chord in the octaves of the held keys, one note per step, with steps
+/// a fixed subdivision of the beat heard by the onset detector.
+pub(crate) struct Arpeggiator {
+    pub(crate) pattern: Pattern,
+    /// Steps per beat.
+    pub(crate) rate: u32,
+    /// Fraction of the step each note sounds for.
+    pub(crate) gate: f32,
+    /// Held keys in the order they were pressed: channel, note and velocity.
+    held: Vec<(u8, u8, u8)>,
+    step: usize,
+    next_step: Option<Instant>,
+    last_step: Option<Instant>,
+    /// The held key whose voice sounds the current note, and when to release it.
+    sounding: Option<((u8, u8), Instant)>,
+    tempo: Tempo,
+    rng: StdRng,
+}
+impl Arpeggiator {
+    pub(crate) fn new(pattern: Pattern, rate: u32, gate: f32) -> Self {
+        Self {
+            pattern,
+            rate: rate.max(1),
+            gate: gate.clamp(0.05, 1.0),
+            held: vec![],
+            step: 0,
+            next_step: None,
+            last_step: None,
+            sounding: None,
+            tempo: Tempo::default(),
+            rng: StdRng::seed_from_u64(0),
+        }
+    }
+    fn step_length(&self) -> Duration {
+        self.tempo.beat_length / self.rate
+    }
+    /// Updates the tempo and realigns the steps to a beat heard at `now`. Onsets between beats,
+    /// e.g. strums, are ignored.
+    pub(crate) fn beat(&mut self, now: Instant) {
+        if self.tempo.off_beat(now) {
+            return;
+        }
+        self.tempo.beat(now);
+        if self.next_step.is_some() {
+            // A step just played counts as on the beat.
+            let just_played = self
+                .last_step
+                .is_some_and(|last| now - last < self.step_length() / 2);
+            self.next_step = Some(if just_played {
+                self.last_step.unwrap() + self.step_length()
+            } else {
+                now
+            });
+        }
+    }
+    pub(crate) fn press(&mut self, channel: u8, note: u8, velocity: u8, now: Instant) {
+        self.held.retain(|&(c, n, _)| (c, n) != (channel, note));
+        self.held.push((channel, note, velocity));
+        if self.next_step.is_none() {
+            self.step = 0;
+            self.next_step = Some(now);
+        }
+    }
+    pub(crate) fn release<O: Output>(&mut self, channel: u8, note: u8, voices: &mut Voices<O>) {
+        self.held.retain(|&(c, n, _)| (c, n) != (channel, note));
+        if self.held.is_empty() {
+            self.stop(voices);
+        }
+    }
+    /// Silences the arpeggio and forgets the held keys.
+    pub(crate) fn stop<O: Output>(&mut self, voices: &mut Voices<O>) {
+        if let Some(((channel, note), _)) = self.sounding.take() {
+            voices.release(channel, note, 0);
+        }
+        self.held.clear();
+        self.next_step = None;
+        self.last_step = None;
+    }
+    /// When `tick` next has something to do.
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        [self.next_step, self.sounding.map(|(_, off)| off)]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+    /// Ends notes whose gate is over and plays the step that is due, if any, from `chord`.
+    pub(crate) fn tick<O: Output>(&mut self, now: Instant, chord: &Chord, voices: &mut Voices<O>) {
+        if let Some(((channel, note), off)) = self.sounding {
+            if now >= off {
+                voices.release(channel, note, 0);
+                self.sounding = None;
+            }
+        }
+        let Some(next) = self.next_step.filter(|&next| now >= next) else {
+            return;
+        };
+        let notes = self.notes(chord);
+        let note = match self.pattern {
+            Pattern::Random => notes[self.rng.gen_range(0..notes.len())],
+            Pattern::Down => notes[notes.len() - 1 - self.step % notes.len()],
+            Pattern::UpDown if notes.len() > 2 => {
+                let cycle = 2 * notes.len() - 2;
+                let i = self.step % cycle;
+                notes[if i < notes.len() { i } else { cycle - i }]
+            }
+            _ => notes[self.step % notes.len()],
+        };
+        self.step += 1;
+        // The first held key's voice plays the arpeggio, so a new note replaces the last one.
+        if let Some(((channel, key), _)) = self.sounding.take() {
+            voices.release(channel, key, 0);
+        }
+        let (channel, key, velocity) = self.held[0];
+        voices.press(channel, key, note, velocity);
+        let step = self.step_length();
+        self.sounding = Some(((channel, key), now + step.mul_f32(self.gate)));
+        self.last_step = Some(now);
+        // Catch up rather than play a burst of late steps.
+        self.next_step = Some((next + step).max(now));
+    }
+    /// The notes to pick from, in pitch order except when playing as played.
+    fn notes(&self, chord: &Chord) -> Vec<u8> {
+        let tones = chord
+            .notes()
+            .iter()
+            .map(|n| n.to_u8().unwrap())
+            .collect::<Vec<_>>();
+        if self.pattern == Pattern::AsPlayed {
+            return self
+                .held
+                .iter()
+                .map(|&(_, note, _)| nearest_tone(note, &tones))
+                .collect();
+        }
+        let mut notes = self
+            .held
+            .iter()
+            .flat_map(|&(_, note, _)| tones.iter().map(move |&t| note / 12 * 12 + t))
+            .filter(|&note| note <= 127)
+            .collect::<Vec<_>>();
+        notes.sort_unstable();
+        notes.dedup();
+        if notes.is_empty() {
+            // Only possible at the very top of the range.
+            notes.push(self.held[0].1);
+        }
+        notes
+    }
+}
+
+/// The chord tone closest to `note`, the lower one on a tie, within the MIDI range.
+fn nearest_tone(note: u8, tones: &[u8]) -> u8 {
+    (0..=6)
+        .flat_map(|d| [note.checked_sub(d), note.checked_add(d)])
+        .flatten()
+        .find(|&n| n <= 127 && tones.contains(&(n % 12)))
+        .unwrap_or(note)
+}
+
+#[test]
+fn arpeggiates_through_chord_changes() {
+    use crate::model::num_to_chord;
+    use crate::voice::messages::{off, offs, ons};
+    let start = Instant::now();
+    let at = |ms| start + Duration::from_millis(ms);
+    let mut voices = Voices::new(vec![]);
+    let mut arpeggiator = Arpeggiator::new(Pattern::UpDown, 2, 0.5);
+    let (c, a_minor) = (num_to_chord(0), num_to_chord(19));
+    arpeggiator.press(0, 62, 100, at(0));
+    // Eighth notes at 120 bpm, switching to A minor on the third step.
+    for ms in (0..1000).step_by(50) {
+        let chord = if ms < 500 { &c } else { &a_minor };
+        arpeggiator.tick(at(ms), chord, &mut voices);
+    }
+    assert_eq!(ons(&voices.output), [60, 64, 69, 64]);
+    assert_eq!(voices.output[1], off(0, 60, 0));
+
+    // A beat heard early pulls the next step in, and releasing stops without hanging notes.
+    arpeggiator.beat(at(1050));
+    assert_eq!(arpeggiator.deadline(), Some(at(1050)));
+    arpeggiator.tick(at(1050), &a_minor, &mut voices);
+    // A strum between beats leaves the steps where they are.
+    arpeggiator.beat(at(1100));
+    assert_eq!(arpeggiator.next_step, Some(at(1300)));
+    arpeggiator.release(0, 62, &mut voices);
+    assert_eq!(arpeggiator.deadline(), None);
+    assert_eq!(offs(&voices.output).len(), ons(&voices.output).len());
+
+    // As played: the keys in order, each on its nearest chord tone.
+    let mut arpeggiator = Arpeggiator::new(Pattern::AsPlayed, 4, 1.0);
+    arpeggiator.press(0, 66, 100, at(0));
+    arpeggiator.press(0, 61, 100, at(0));
+    assert_eq!(arpeggiator.notes(&c), [67, 60]);
+}
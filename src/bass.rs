@@ -0,0 +1,227 @@
+use std::time::Instant;
+
+use chords::Chord;
+use clap::ValueEnum;
+use num::ToPrimitive;
+
+use crate::model::chord_to_num;
+use crate::tempo::Tempo;
+use crate::voice::{Output, Voices};
+
+/// The lowest root the bass plays, E1.
+const LOWEST_ROOT: u8 = 28;
+const VELOCITY: u8 = 100;
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BassStyle {
+    /// The root on the downbeat, held through the bar.
+    Roots,
+    /// The root on the downbeat and the fifth half way through the bar.
+    RootFifth,
+    /// A chord tone on every beat, and a chromatic approach to the next bar's root on the last.
+    Walking,
+    /// The root on every beat and its octave in between.
+    Octaves,
+}
+
+/// Plays a bass line from the current chord on an output of its own, a step on each beat heard.
+/// Following a chart, bars are where the chart puts them; otherwise a chord change starts a new
+/// bar. Onsets between beats are ignored, and the bass stops after a bar without beats.
+pub(crate) struct Bass<O: Output> {
+    voices: Voices<O>,
+    style: BassStyle,
+    channel: u8,
+    beats_per_bar: usize,
+    chord: Option<usize>,
+    /// The chart's next chord and how many beats away it is, when following a chart.
+    upcoming: Option<(Chord, usize)>,
+    /// The chart's beat in the bar being heard, until the next beat.
+    chart_beat: Option<usize>,
+    beat_in_bar: usize,
+    last_note: Option<u8>,
+    /// The octave to play between beats, and when.
+    offbeat: Option<(Instant, u8)>,
+    /// When to release the last note if no beat comes first.
+    release: Option<Instant>,
+    tempo: Tempo,
+}
+impl<O: Output> Bass<O> {
+    pub(crate) fn new(output: O, style: BassStyle, channel: u8, beats_per_bar: usize) -> Self {
+        Self {
+            voices: Voices::new(output),
+            style,
+            channel,
+            beats_per_bar: beats_per_bar.max(1),
+            chord: None,
+            upcoming: None,
+            chart_beat: None,
+            beat_in_bar: 0,
+            last_note: None,
+            offbeat: None,
+            release: None,
+            tempo: Tempo::default(),
+        }
+    }
+    /// Where the chart is: the next chord, how many beats away it is, and which beat of the bar
+    /// is being heard.
+    pub(crate) fn upcoming(&mut self, chord: Chord, beats: usize, beat_in_bar: usize) {
+        self.upcoming = Some((chord, beats));
+        self.chart_beat = Some(beat_in_bar);
+    }
+    /// Moves to `chord` as soon as it is detected rather than on the next beat, while the bass
+    /// is playing.
+    pub(crate) fn change(&mut self, now: Instant, chord: &Chord) {
+        let num = chord_to_num(chord);
+        if self.release.is_none() || self.chord.is_none_or(|c| c == num) {
+            return;
+        }
+        self.chord = Some(num);
+        if self.upcoming.is_none() {
+            self.beat_in_bar = 0;
+        }
+        self.offbeat = None;
+        self.play(now, bass_root(chord));
+    }
+    /// Plays the step for a beat heard at `now` over `chord`.
+    pub(crate) fn beat(&mut self, now: Instant, chord: &Chord) {
+        if self.tempo.off_beat(now) {
+            return;
+        }
+        self.tempo.beat(now);
+        let num = chord_to_num(chord);
+        if let Some(beat) = self.chart_beat.take() {
+            // The beat after the one the chart last placed.
+            self.beat_in_bar = (beat + 1) % self.beats_per_bar;
+            self.chord = Some(num);
+        } else if self.chord == Some(num) {
+            self.beat_in_bar = (self.beat_in_bar + 1) % self.beats_per_bar;
+        } else {
+            self.chord = Some(num);
+            self.beat_in_bar = 0;
+        }
+        self.release = Some(now + self.tempo.beat_length * self.beats_per_bar as u32);
+        let root = bass_root(chord);
+        let i = self.beat_in_bar;
+        let note = match self.style {
+            BassStyle::Roots => (i == 0).then_some(root),
+            BassStyle::RootFifth if i == 0 => Some(root),
+            BassStyle::RootFifth => (i == self.beats_per_bar / 2).then_some(root + 7),
+            BassStyle::Walking if i == 0 => Some(root),
+            BassStyle::Walking if i + 1 == self.beats_per_bar => Some(self.approach(chord)),
+            BassStyle::Walking => {
+                let tones = chord.notes();
+                let tone = tones[i % tones.len()].to_u8().unwrap();
+                Some(root + (tone + 12 - root % 12) % 12)
+            }
+            BassStyle::Octaves => {
+                self.offbeat = Some((now + self.tempo.beat_length / 2, root + 12));
+                Some(root)
+            }
+        };
+        if let Some(note) = note {
+            self.play(now, note);
+        }
+    }
+    /// A semitone from the root of the chord expected next bar, on the side the line comes from.
+    fn approach(&self, chord: &Chord) -> u8 {
+        let next = match &self.upcoming {
+            Some((next, beats)) if *beats <= 1 => next,
+            _ => chord,
+        };
+        let target = bass_root(next);
+        match self.last_note {
+            Some(last) if last > target => target + 1,
+            _ => target - 1,
+        }
+    }
+    fn play(&mut self, now: Instant, note: u8) {
+        self.voices.press(self.channel, 0, note, VELOCITY);
+        self.last_note = Some(note);
+        self.release
+            .get_or_insert(now + self.tempo.beat_length * self.beats_per_bar as u32);
+    }
+    /// When `tick` next has something to do.
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        [self.offbeat.map(|(at, _)| at), self.release]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+    /// Plays the note between beats if it is due, and releases the last note once a bar has
+    /// passed without a beat.
+    pub(crate) fn tick(&mut self, now: Instant) {
+        if let Some((at, note)) = self.offbeat {
+            if now >= at {
+                self.offbeat = None;
+                self.play(now, note);
+            }
+        }
+        if self.release.is_some_and(|at| now >= at) {
+            self.stop();
+        }
+    }
+    /// Silences the bass, e.g. on panic. It comes back in on the next beat.
+    pub(crate) fn stop(&mut self) {
+        self.chord = None;
+        self.offbeat = None;
+        self.release = None;
+        self.voices.all_notes_off();
+    }
+}
+
+/// The chord's root in the octave from E1 up.
+fn bass_root(chord: &Chord) -> u8 {
+    let root = chord.root.to_u8().unwrap();
+    LOWEST_ROOT + (root + 12 - LOWEST_ROOT % 12) % 12
+}
+
+#[test]
+fn walks_and_pumps_with_the_chords() {
+    use crate::midi::MidiMessage;
+    use crate::model::num_to_chord;
+    use crate::voice::messages::{off, offs, ons};
+    use std::time::Duration;
+    let start = Instant::now();
+    let at = |beat: u32| start + Duration::from_millis(500) * beat;
+    let notes = |bass: &Bass<Vec<MidiMessage>>| ons(&bass.voices.output);
+    let (c, a_minor, g) = (num_to_chord(0), num_to_chord(19), num_to_chord(14));
+    let mut bass = Bass::new(vec![], BassStyle::Walking, 1, 4);
+    // A bar of C, walking back to C for all it knows, then A minor: C E G Db, A C E ...
+    for beat in 0..7 {
+        let chord = if beat < 4 { &c } else { &a_minor };
+        bass.beat(at(beat), chord);
+    }
+    assert_eq!(notes(&bass), [36, 40, 43, 37, 33, 36, 40]);
+    // Following a chart, the last beat leads to the chord coming up instead.
+    bass.upcoming(g.clone(), 1, 2);
+    bass.beat(at(7), &a_minor);
+    assert_eq!(notes(&bass).last(), Some(&32));
+    // An onset between beats is not a beat.
+    bass.beat(at(7) + Duration::from_millis(100), &a_minor);
+    assert_eq!(notes(&bass).len(), 8);
+    // A chord change is played at once, and the chart keeps the bar where it was.
+    bass.change(at(8), &g);
+    assert_eq!(notes(&bass).last(), Some(&31));
+    bass.upcoming(c.clone(), 1, 1);
+    bass.beat(at(8), &g);
+    assert_eq!(bass.beat_in_bar, 2);
+    // After a bar without beats the bass stops.
+    assert_eq!(bass.deadline(), Some(at(12)));
+    bass.tick(at(12));
+    let last = *notes(&bass).last().unwrap();
+    assert_eq!(bass.voices.output.last(), Some(&off(1, last, 0)));
+    assert_eq!(bass.deadline(), None);
+
+    let mut bass = Bass::new(vec![], BassStyle::Octaves, 1, 4);
+    bass.beat(at(0), &c);
+    assert_eq!(bass.deadline(), Some(start + Duration::from_millis(250)));
+    bass.tick(at(1));
+    bass.beat(at(1), &a_minor);
+    bass.stop();
+    // Stopped, chord changes wait for the next beat.
+    bass.change(at(2), &g);
+    assert_eq!(notes(&bass), [36, 48, 33]);
+    assert_eq!(bass.deadline(), None);
+    // Each note ends before the next, and stopping ends the last.
+    assert_eq!(offs(&bass.voices.output), [36, 48, 33]);
+}
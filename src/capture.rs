@@ -0,0 +1,117 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+
+use chords::{Chord, ChordType};
+use itertools::Itertools;
+use num::ToPrimitive;
+
+use crate::model::{rotate_to_root, Observation};
+
+const MAJOR_SAMPLES: &str = "maj_samples.json";
+const MINOR_SAMPLES: &str = "min_samples.json";
+/// Captured samples to collect before the files are rewritten.
+const SAVE_EVERY: usize = 32;
+
+/// Labelled chroma samples, root-rotated to C, split by chord quality like `data/*_samples.json`.
+#[derive(Default)]
+pub(crate) struct Samples {
+    pub(crate) major: Vec<Vec<f32>>,
+    pub(crate) minor: Vec<Vec<f32>>,
+}
+impl Samples {
+    pub(crate) fn load(dir: &Path) -> Self {
+        Self {
+            major: read_samples(&dir.join(MAJOR_SAMPLES)),
+            minor: read_samples(&dir.join(MINOR_SAMPLES)),
+        }
+    }
+    /// Writes each file whole under a temporary name first, so a crash never leaves half of one.
+    pub(crate) fn save(&self, dir: &Path) {
+        std::fs::create_dir_all(dir).unwrap();
+        for (file, samples) in [(MAJOR_SAMPLES, &self.major), (MINOR_SAMPLES, &self.minor)] {
+            let path = dir.join(file);
+            let temporary = path.with_extension("json.tmp");
+            std::fs::write(&temporary, serde_json::to_string(samples).unwrap()).unwrap();
+            std::fs::rename(&temporary, &path).unwrap();
+        }
+    }
+    pub(crate) fn push(&mut self, chord: &Chord, observation: &Observation) {
+        let sample = rotate_to_root(observation, chord.root.to_usize().unwrap())
+            .iter()
+            .copied()
+            .collect();
+        match chord.chord_type {
+            ChordType::Major => self.major.push(sample),
+            ChordType::Minor => self.minor.push(sample),
+            _ => unreachable!("Only major and minor chords are supported"),
+        }
+    }
+}
+
+fn read_samples(path: &Path) -> Vec<Vec<f32>> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => serde_json::from_str(&contents)
+            .unwrap_or_else(|e| panic!("Failed to parse samples {}: {e}", path.display())),
+        Err(_) => vec![],
+    }
+}
+
+/// Parses the label of a capture, e.g. "Am". Only major and minor chords can be captured.
+pub(crate) fn parse_chord(label: &str) -> Option<Chord> {
+    let chord: Chord = label.parse().ok()?;
+    match chord.chord_type {
+        ChordType::Major | ChordType::Minor => Some(chord),
+        _ => None,
+    }
+}
+
+/// Adds every captured observation to the sample files in `dir`, off the audio thread. The files
+/// are saved every `SAVE_EVERY` samples, and when `None` is sent as capturing stops.
+pub(crate) fn spawn_writer(dir: PathBuf) -> mpsc::Sender<Option<(Chord, Observation)>> {
+    let (tx, rx) = mpsc::channel::<Option<(Chord, Observation)>>();
+    thread::spawn(move || {
+        let mut samples = Samples::load(&dir);
+        let mut unsaved = 0;
+        for captured in rx {
+            if let Some((chord, observation)) = &captured {
+                samples.push(chord, observation);
+                unsaved += 1;
+            }
+            if unsaved > 0 && (captured.is_none() || unsaved >= SAVE_EVERY) {
+                samples.save(&dir);
+                unsaved = 0;
+            }
+        }
+        if unsaved > 0 {
+            samples.save(&dir);
+        }
+    });
+    tx
+}
+
+/// Appends every observation to `path`, one JSON array per line, for offline analysis.
+pub(crate) fn spawn_recorder(path: PathBuf) -> mpsc::Sender<Observation> {
+    let (tx, rx) = mpsc::channel::<Observation>();
+    thread::spawn(move || {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .unwrap_or_else(|e| panic!("Failed to open recording {}: {e}", path.display()));
+        for observation in rx {
+            let observation = observation.iter().collect_vec();
+            writeln!(file, "{}", serde_json::to_string(&observation).unwrap()).unwrap();
+        }
+    });
+    tx
+}
+
+pub(crate) fn read_recording(path: &Path) -> Vec<Observation> {
+    std::fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("Failed to read recording {}: {e}", path.display()))
+        .lines()
+        .map(|line| Observation::from_vec(serde_json::from_str(line).unwrap()))
+        .collect()
+}
@@ -0,0 +1,249 @@
+use std::path::Path;
+
+use chords::Chord;
+use itertools::Itertools;
+
+use crate::model::{num_to_chord, Model, Observation};
+use crate::progression::{parse_chord_token, parse_chordpro};
+
+/// Per observation: probability of staying on the same beat, e.g. for an extra onset.
+const STAY: f32 = 0.1;
+/// Probability of moving on by two beats, e.g. for a missed onset.
+const SKIP: f32 = 0.05;
+/// Probability of jumping to the start of any bar, for repeats and skipped sections.
+const JUMP: f32 = 0.05;
+const ADVANCE: f32 = 1.0 - STAY - SKIP - JUMP;
+
+/// An expected chord chart, one chord per beat.
+#[derive(Debug)]
+pub(crate) struct Chart {
+    beats: Vec<usize>,
+    beats_per_bar: usize,
+}
+impl Chart {
+    fn chord_at(&self, beat: usize) -> usize {
+        self.beats[beat % self.beats.len()]
+    }
+    /// How many beats after `beat` the chord changes, and to what.
+    fn next_change(&self, beat: usize) -> (usize, usize) {
+        let chord = self.chord_at(beat);
+        let beats_to_next = (1..=self.beats.len())
+            .find(|&d| self.chord_at(beat + d) != chord)
+            .unwrap_or(self.beats.len());
+        (beats_to_next, self.chord_at(beat + beats_to_next))
+    }
+}
+
+/// Reads a chart. ChordPro charts (`.cho`, `.chopro`, `.chordpro`) give each chord one bar; see
+/// `parse_chart` for plain text.
+pub(crate) fn read_chart(path: &Path, beats_per_bar: usize) -> Chart {
+    let contents = std::fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("Failed to read chart {}: {e}", path.display()));
+    let chart = match path.extension().and_then(|e| e.to_str()) {
+        Some("cho" | "chopro" | "chordpro") => to_chart(
+            &parse_chordpro(&contents)
+                .into_iter()
+                .map(|chord| vec![chord])
+                .collect_vec(),
+            beats_per_bar,
+        ),
+        _ => parse_chart(&contents, beats_per_bar),
+    }
+    .unwrap_or_else(|e| panic!("Invalid chart {}: {e}", path.display()));
+    assert!(
+        !chart.beats.is_empty(),
+        "No chords in chart {}",
+        path.display()
+    );
+    chart
+}
+
+/// Parses a plain text chart such as `| C | G*2 | Am F |`. Bars are separated by `|`, `G*2`
+/// fills two bars and chords sharing a bar split its beats evenly. Without any `|`, every chord
+/// is a bar. Bars with more chords than beats are refused.
+pub(crate) fn parse_chart(contents: &str, beats_per_bar: usize) -> Result<Chart, String> {
+    to_chart(&parse_bars(contents), beats_per_bar)
+}
+
+fn parse_bars(contents: &str) -> Vec<Vec<usize>> {
+    let cells = if contents.contains('|') {
+        contents.split('|').map(str::to_string).collect_vec()
+    } else {
+        contents
+            .split_whitespace()
+            .map(str::to_string)
+            .collect_vec()
+    };
+    let mut bars = vec![];
+    for cell in cells {
+        let tokens = cell.split_whitespace().collect_vec();
+        match tokens[..] {
+            [] => {}
+            [token] => {
+                let (symbol, count) = match token.split_once('*') {
+                    Some((symbol, count)) => (symbol, count.parse().unwrap_or(1)),
+                    None => (token, 1),
+                };
+                if let Some(chord) = parse_chord_token(symbol) {
+                    bars.resize(bars.len() + count, vec![chord]);
+                }
+            }
+            _ => {
+                let chords = tokens
+                    .into_iter()
+                    .filter_map(parse_chord_token)
+                    .collect_vec();
+                if !chords.is_empty() {
+                    bars.push(chords);
+                }
+            }
+        }
+    }
+    bars
+}
+
+fn to_chart(bars: &[Vec<usize>], beats_per_bar: usize) -> Result<Chart, String> {
+    let mut beats = vec![];
+    for (i, bar) in bars.iter().enumerate() {
+        if bar.len() > beats_per_bar {
+            return Err(format!(
+                "bar {} has {} chords but only {beats_per_bar} beats",
+                i + 1,
+                bar.len()
+            ));
+        }
+        let share = beats_per_bar / bar.len();
+        let first = beats_per_bar - share * (bar.len() - 1);
+        for (i, &chord) in bar.iter().enumerate() {
+            let count = if i == 0 { first } else { share };
+            beats.resize(beats.len() + count, chord);
+        }
+    }
+    Ok(Chart {
+        beats,
+        beats_per_bar,
+    })
+}
+
+/// Where the performance is in the chart.
+#[derive(Debug)]
+pub(crate) struct Position {
+    pub(crate) bar: usize,
+    pub(crate) beat_in_bar: usize,
+    /// Filtered posterior probability of `beat`.
+    pub(crate) confidence: f32,
+    pub(crate) chord: Chord,
+    /// Filtered posterior probability of `chord`, summed over the beats that have it.
+    pub(crate) chord_confidence: f32,
+    /// The chord of the following beat.
+    pub(crate) upcoming: Chord,
+    /// The next different chord, `beats_to_next` beats from now.
+    pub(crate) next_chord: Chord,
+    pub(crate) beats_to_next: usize,
+    /// Filtered posterior probability that `next_chord` comes next, summed over the beats it
+    /// follows.
+    pub(crate) next_confidence: f32,
+}
+
+/// Online alignment of beat observations to a chart, by forward filtering over its beats. The
+/// chart repeats from the top after its last beat.
+pub(crate) struct Follower {
+    chart: Chart,
+    /// Filtered posterior over chart beats after the last pushed observation.
+    forward: Vec<f32>,
+}
+impl Follower {
+    pub(crate) fn new(chart: Chart) -> Self {
+        // Before the first observation, so that the first advance lands on beat 0.
+        let mut forward = vec![0.0; chart.beats.len()];
+        forward[chart.beats.len() - 1] = 1.0;
+        Self { chart, forward }
+    }
+    /// Adds the last of `context`, a completed beat.
+    pub(crate) fn push(&mut self, model: &Model, context: &[Observation]) {
+        self.forward = self.step(model, context);
+    }
+    /// The position with the last of `context` as a provisional beat.
+    pub(crate) fn peek(&self, model: &Model, context: &[Observation]) -> Position {
+        let forward = self.step(model, context);
+        let (beat, &confidence) = forward
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .unwrap();
+        let chord = self.chart.chord_at(beat);
+        let (beats_to_next, next_chord) = self.chart.next_change(beat);
+        let summed = |matches: &dyn Fn(usize) -> bool| -> f32 {
+            (0..forward.len())
+                .filter(|&s| matches(s))
+                .map(|s| forward[s])
+                .sum()
+        };
+        Position {
+            bar: beat / self.chart.beats_per_bar,
+            beat_in_bar: beat % self.chart.beats_per_bar,
+            confidence,
+            chord: num_to_chord(chord),
+            chord_confidence: summed(&|s| self.chart.chord_at(s) == chord),
+            upcoming: num_to_chord(self.chart.chord_at(beat + 1)),
+            next_chord: num_to_chord(next_chord),
+            beats_to_next,
+            next_confidence: summed(&|s| self.chart.next_change(s).1 == next_chord),
+        }
+    }
+    fn step(&self, model: &Model, context: &[Observation]) -> Vec<f32> {
+        let n = self.chart.beats.len();
+        let emissions = model.log_emissions(context);
+        let max = emissions.max();
+        let bar_starts = (0..n).step_by(self.chart.beats_per_bar).collect_vec();
+        let mut forward = (0..n)
+            .map(|s| {
+                let previous = |d: usize| self.forward[(s + n - d % n) % n];
+                STAY * previous(0) + ADVANCE * previous(1) + SKIP * previous(2)
+            })
+            .collect_vec();
+        for &s in &bar_starts {
+            forward[s] += JUMP / bar_starts.len() as f32;
+        }
+        for (s, f) in forward.iter_mut().enumerate() {
+            *f *= (emissions[self.chart.beats[s]] - max).exp();
+        }
+        let total: f32 = forward.iter().sum();
+        forward.iter_mut().for_each(|f| *f /= total);
+        forward
+    }
+}
+
+#[test]
+fn follows_repeats_through_chart() {
+    use crate::model::template_observation;
+    let chart = parse_chart("| C | G | Am | F |\n| Dm | G | C*2 |", 4).unwrap();
+    assert_eq!(chart.beats.len(), 32);
+    assert_eq!(parse_chart("| C G |", 4).unwrap().beats, vec![0, 0, 14, 14]);
+    assert_eq!(parse_chart("C Am", 2).unwrap().beats, vec![0, 0, 19, 19]);
+    assert!(parse_chart("| C F G |", 2).is_err());
+
+    let model = Model::default();
+    let mut follower = Follower::new(chart);
+    // The first four bars twice, then the rest of the chart.
+    let performance = [0, 1, 2, 3, 0, 1, 2, 3, 4, 5, 6, 7]
+        .iter()
+        .flat_map(|&bar| [bar; 4].into_iter().enumerate())
+        .map(|(beat, bar)| template_observation(follower.chart.beats[bar * 4 + beat]))
+        .collect_vec();
+    for t in 0..performance.len() - 1 {
+        follower.push(&model, &performance[..=t]);
+        if t == 4 * 5 + 1 {
+            let position = follower.peek(&model, &performance[..=t + 1]);
+            assert_eq!((position.bar, position.beat_in_bar), (1, 2));
+            assert_eq!(position.beats_to_next, 2);
+            assert_eq!(chords::ChordType::Minor, position.next_chord.chord_type);
+        }
+    }
+    let position = follower.peek(&model, &performance);
+    assert_eq!((position.bar, position.beat_in_bar), (7, 3));
+    // Shared with the neighbouring beats of the same chord, which all agree on the chord.
+    assert!(position.confidence > 0.25);
+    assert!(position.chord_confidence > 0.9);
+    assert!(position.next_confidence >= position.chord_confidence);
+}
@@ -0,0 +1,129 @@
+use chords::Chord;
+use num::ToPrimitive;
+
+use crate::midi::MidiMessage;
+use crate::model::chord_to_num;
+use crate::voice::{Output, Voices};
+use crate::voicing::{Voicer, VoicingStyle};
+
+/// Controller carrying the chord's root, C to B spread evenly over 0-127.
+const ROOT_CC: u8 = 20;
+/// Controller carrying the chord's quality, 0 for major and 127 for minor.
+const QUALITY_CC: u8 = 21;
+/// Where chords are held, from C3 up.
+const REGISTER: u8 = 48;
+const VELOCITY: u8 = 90;
+
+/// Holds the chord in effect as notes on a channel of its own, changing them only when the chord
+/// changes and keeping the notes chords share, and sends the chord's root and quality as
+/// controllers for automation.
+pub(crate) struct ChordTrack<O: Output> {
+    voices: Voices<O>,
+    channel: u8,
+    /// Voice-leads the chords if given, otherwise they are held in root position.
+    voicer: Option<Voicer>,
+    chord: Option<usize>,
+    held: Vec<u8>,
+}
+impl<O: Output> ChordTrack<O> {
+    pub(crate) fn new(output: O, channel: u8, voicing: Option<VoicingStyle>) -> Self {
+        Self {
+            voices: Voices::new(output),
+            channel,
+            voicer: voicing.map(Voicer::new),
+            chord: None,
+            held: vec![],
+        }
+    }
+    pub(crate) fn update(&mut self, chord: &Chord) {
+        let num = chord_to_num(chord);
+        if self.chord == Some(num) {
+            return;
+        }
+        self.chord = Some(num);
+        let tones = chord
+            .notes()
+            .iter()
+            .map(|n| n.to_u8().unwrap())
+            .collect::<Vec<_>>();
+        let notes = match &mut self.voicer {
+            Some(voicer) => voicer.voice(REGISTER, &tones),
+            None => tones
+                .iter()
+                .map(|&t| REGISTER + tones[0] + (t + 12 - tones[0]) % 12)
+                .collect(),
+        };
+        for &note in &self.held {
+            if !notes.contains(&note) {
+                self.voices.release(self.channel, note, 0);
+            }
+        }
+        for &note in &notes {
+            if !self.held.contains(&note) {
+                self.voices.press(self.channel, note, note, VELOCITY);
+            }
+        }
+        self.held = notes;
+        for (controller, value) in [(ROOT_CC, num / 2 * 127 / 11), (QUALITY_CC, num % 2 * 127)] {
+            self.voices.output.send(MidiMessage::ControlChange {
+                channel: self.channel,
+                controller,
+                value: value as u8,
+            });
+        }
+    }
+    /// Releases the chord, e.g. on panic. The track is silent until the chord changes.
+    pub(crate) fn stop(&mut self) {
+        self.voices.all_notes_off();
+        self.held.clear();
+    }
+}
+
+#[test]
+fn holds_chords_until_they_change() {
+    use crate::model::num_to_chord;
+    use crate::voice::messages;
+    let on = |note| messages::on(15, note, VELOCITY);
+    let off = |note| messages::off(15, note, 0);
+    let cc = |controller, value| messages::cc(15, controller, value);
+    let mut track = ChordTrack::new(vec![], 15, None);
+    track.update(&num_to_chord(0));
+    assert_eq!(
+        track.voices.output,
+        [on(48), on(52), on(55), cc(ROOT_CC, 0), cc(QUALITY_CC, 0)]
+    );
+    // A minor replaces C, and hearing it again changes nothing.
+    track.voices.output.clear();
+    track.update(&num_to_chord(19));
+    track.update(&num_to_chord(19));
+    assert_eq!(
+        track.voices.output,
+        [
+            off(48),
+            off(52),
+            off(55),
+            on(57),
+            on(60),
+            on(64),
+            cc(ROOT_CC, 103),
+            cc(QUALITY_CC, 127)
+        ]
+    );
+    track.voices.output.clear();
+    track.stop();
+    assert_eq!(track.voices.output, [off(57), off(60), off(64)]);
+    // Stopped, it stays silent until the chord changes.
+    track.voices.output.clear();
+    track.update(&num_to_chord(19));
+    assert!(track.voices.output.is_empty());
+    track.update(&num_to_chord(0));
+    assert_eq!(track.voices.output[..3], [on(48), on(52), on(55)]);
+
+    // Voiced, chords move as little as they can, and C holds through to F.
+    let mut track = ChordTrack::new(vec![], 15, Some(VoicingStyle::Close));
+    track.update(&num_to_chord(0));
+    track.voices.output.clear();
+    track.update(&num_to_chord(10));
+    assert!(track.voices.output.contains(&on(53)));
+    assert!(!track.voices.output.contains(&off(48)));
+}
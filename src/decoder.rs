@@ -0,0 +1,414 @@
+use std::collections::VecDeque;
+
+use itertools::Itertools;
+
+use crate::key::{Key, KeyInference, KeyTransitions, NUM_KEYS};
+use crate::model::{num_to_chord, MChords, Model, Observation, VChords, NUM_CHORDS};
+
+/// A state of the joint HMM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct State {
+    pub(crate) key: usize,
+    pub(crate) chord: usize,
+}
+impl State {
+    fn from_index(index: usize) -> Self {
+        Self {
+            key: index / NUM_CHORDS,
+            chord: index % NUM_CHORDS,
+        }
+    }
+    fn index(&self) -> usize {
+        self.key * NUM_CHORDS + self.chord
+    }
+}
+
+/// Backpointers into the previous step, indexed by `[key][chord]`.
+type Pointers = Vec<[usize; NUM_CHORDS]>;
+
+/// Parameters of the joint (key, chord) HMM.
+///
+/// Within a key, chord transitions are the model's transitions reweighted by how typical the
+/// target chord is in that key. The key itself changes with probability `key_change` per
+/// observation. Kept until the model's parameters change, e.g. as it adapts.
+struct JointParams {
+    source_initial: VChords,
+    log_initial: Vec<VChords>,
+    log_transitions: KeyTransitions,
+    /// `log_transitions` out of log space, for the forward and backward passes.
+    transitions: Vec<MChords>,
+    key_change: f32,
+}
+impl JointParams {
+    fn new(model: &Model, key_change: f32) -> Self {
+        let log_transitions = KeyTransitions::new(model);
+        Self {
+            source_initial: *model.log_initial(),
+            log_initial: initial_by_key(model),
+            transitions: exp_by_key(&log_transitions),
+            log_transitions,
+            key_change,
+        }
+    }
+    /// `cached`, built for `model` or brought up to date with it.
+    fn cached<'a>(cached: &'a mut Option<Self>, model: &Model, key_change: f32) -> &'a Self {
+        let params = cached.get_or_insert_with(|| Self::new(model, key_change));
+        if *model.log_initial() != params.source_initial {
+            params.source_initial = *model.log_initial();
+            params.log_initial = initial_by_key(model);
+        }
+        if params.log_transitions.update(model) {
+            params.transitions = exp_by_key(&params.log_transitions);
+        }
+        params
+    }
+}
+/// The model's initial chords reweighted within each key, with the keys equally likely.
+fn initial_by_key(model: &Model) -> Vec<VChords> {
+    (0..NUM_KEYS)
+        .map(|k| {
+            let key = Key::from_index(k);
+            let mut initial =
+                VChords::from_fn(|c, _| (model.log_initial()[c]).exp() * key.chord_weight(c));
+            initial /= initial.sum() * NUM_KEYS as f32;
+            initial.map(f32::ln)
+        })
+        .collect()
+}
+/// Each key's chord transitions as probabilities.
+fn exp_by_key(log_transitions: &KeyTransitions) -> Vec<MChords> {
+    (0..NUM_KEYS)
+        .map(|k| log_transitions.in_key(k).map(f32::exp))
+        .collect()
+}
+
+/// One step of the trellis: Viterbi scores (shifted so the best is 0) and the filtered forward
+/// posterior, both per key.
+#[derive(Clone)]
+struct Column {
+    viterbi: Vec<VChords>,
+    forward: Vec<VChords>,
+}
+impl Column {
+    fn initial(params: &JointParams, emission: &VChords) -> Self {
+        let viterbi = params
+            .log_initial
+            .iter()
+            .map(|i| i + emission)
+            .collect_vec();
+        let max_emission = emission.max();
+        let forward = viterbi
+            .iter()
+            .map(|v| v.map(|x| (x - max_emission).exp()))
+            .collect();
+        Self { viterbi, forward }.normalized()
+    }
+    fn next(&self, params: &JointParams, emission: &VChords) -> (Self, Pointers) {
+        let log_stay = (1.0 - params.key_change).ln();
+        let log_move = (params.key_change / (NUM_KEYS - 1) as f32).ln();
+        // Best and second best key for each previous chord, for leaving a key.
+        let best_keys = (0..NUM_CHORDS)
+            .map(|c| {
+                let mut ranked = (0..NUM_KEYS)
+                    .sorted_by(|&a, &b| self.viterbi[b][c].total_cmp(&self.viterbi[a][c]));
+                (ranked.next().unwrap(), ranked.next().unwrap())
+            })
+            .collect_vec();
+        let mut viterbi = vec![VChords::repeat(f32::NEG_INFINITY); NUM_KEYS];
+        let mut pointers = vec![[0; NUM_CHORDS]; NUM_KEYS];
+        for k in 0..NUM_KEYS {
+            for c_next in 0..NUM_CHORDS {
+                for (c, &(first, second)) in best_keys.iter().enumerate() {
+                    let transition = params.log_transitions.in_key(k)[(c, c_next)];
+                    let stay = self.viterbi[k][c] + log_stay;
+                    let other = if first == k { second } else { first };
+                    let moved = self.viterbi[other][c] + log_move;
+                    let (value, from) = if stay >= moved {
+                        (stay, k)
+                    } else {
+                        (moved, other)
+                    };
+                    if value + transition > viterbi[k][c_next] {
+                        viterbi[k][c_next] = value + transition;
+                        pointers[k][c_next] = State {
+                            key: from,
+                            chord: c,
+                        }
+                        .index();
+                    }
+                }
+                viterbi[k][c_next] += emission[c_next];
+            }
+        }
+
+        let max_emission = emission.max();
+        let emission = emission.map(|e| (e - max_emission).exp());
+        let totals = VChords::from_fn(|c, _| self.forward.iter().map(|f| f[c]).sum());
+        let forward = (0..NUM_KEYS)
+            .map(|k| {
+                let previous = VChords::from_fn(|c, _| {
+                    self.forward[k][c] * (1.0 - params.key_change)
+                        + (totals[c] - self.forward[k][c]) * params.key_change
+                            / (NUM_KEYS - 1) as f32
+                });
+                (params.transitions[k].transpose() * previous).component_mul(&emission)
+            })
+            .collect();
+        (Self { viterbi, forward }.normalized(), pointers)
+    }
+    /// Keeps scores bounded over an unlimited session; neither rescaling changes a decision.
+    fn normalized(mut self) -> Self {
+        let max = self
+            .viterbi
+            .iter()
+            .map(|v| v.max())
+            .fold(f32::NEG_INFINITY, f32::max);
+        self.viterbi.iter_mut().for_each(|v| v.add_scalar_mut(-max));
+        let total: f32 = self.forward.iter().map(|f| f.sum()).sum();
+        self.forward.iter_mut().for_each(|f| *f /= total);
+        self
+    }
+    fn best(&self) -> State {
+        (0..NUM_KEYS)
+            .cartesian_product(0..NUM_CHORDS)
+            .map(|(key, chord)| State { key, chord })
+            .max_by(|a, b| self.viterbi[a.key][a.chord].total_cmp(&self.viterbi[b.key][b.chord]))
+            .unwrap()
+    }
+}
+
+/// The best path ending in `column`, one state per entry of `pointers` (oldest first). The
+/// oldest entry's pointers lead out of the path and are not followed.
+fn backtrack(column: &Column, pointers: &[&Pointers]) -> Vec<State> {
+    let mut state = column.best();
+    let mut path = vec![state];
+    for step in pointers[1..].iter().rev() {
+        state = State::from_index(step[state.key][state.chord]);
+        path.push(state);
+    }
+    path.reverse();
+    path
+}
+
+/// Fixed-lag Viterbi decoder for the joint key and chord HMM.
+///
+/// Each observation extends the trellis by one step. Once `lag` newer observations have arrived
+/// the state of an observation is committed to the session history, which is never revised and
+/// grows for the whole session.
+pub(crate) struct Decoder {
+    key_change: f32,
+    lag: usize,
+    params: Option<JointParams>,
+    column: Option<Column>,
+    uncommitted: VecDeque<Pointers>,
+    history: Vec<State>,
+}
+impl Decoder {
+    pub(crate) fn new(key_change: f32, lag: usize) -> Self {
+        Self {
+            key_change,
+            lag,
+            params: None,
+            column: None,
+            uncommitted: VecDeque::new(),
+            history: vec![],
+        }
+    }
+    /// Adds the last of `context`, a completed observation, to the trellis.
+    pub(crate) fn push(&mut self, model: &Model, context: &[Observation]) {
+        let (column, pointers) = self.step(model, context);
+        self.uncommitted.push_back(pointers);
+        while self.uncommitted.len() > self.lag {
+            let path = backtrack(&column, &self.uncommitted.iter().collect_vec());
+            self.history.push(path[0]);
+            self.uncommitted.pop_front();
+        }
+        self.column = Some(column);
+    }
+    /// Decodes with the last of `context` as a provisional last step, without adding it to the
+    /// trellis. Returns the chords of the last `window` observations, committed or not.
+    pub(crate) fn peek(
+        &mut self,
+        model: &Model,
+        context: &[Observation],
+        window: usize,
+    ) -> KeyInference {
+        let (column, pointers) = self.step(model, context);
+        let path = backtrack(
+            &column,
+            &self.uncommitted.iter().chain([&pointers]).collect_vec(),
+        );
+        let last = *path.last().unwrap();
+        let mut states = self
+            .history
+            .iter()
+            .chain(&path)
+            .rev()
+            .take(window)
+            .collect_vec();
+        states.reverse();
+        KeyInference {
+            chords: states.iter().map(|s| num_to_chord(s.chord)).collect(),
+            keys: states.iter().map(|s| Key::from_index(s.key)).collect(),
+            key: Key::from_index(last.key),
+            key_confidence: column.forward[last.key].sum(),
+            chord_posterior: column.forward.iter().sum(),
+        }
+    }
+    /// Smoothed chord posteriors given all of `observations`, by forward-backward over the joint
+    /// HMM, marginalized over keys. Independent of the trellis, so it scores any window.
+    pub(crate) fn posteriors(
+        &mut self,
+        model: &Model,
+        observations: &[Observation],
+    ) -> Vec<VChords> {
+        let key_change = self.key_change;
+        let params = JointParams::cached(&mut self.params, model, key_change);
+        let emissions = (0..observations.len())
+            .map(|t| {
+                let emission = model.log_emissions(&observations[..=t]);
+                let max = emission.max();
+                emission.map(|e| (e - max).exp())
+            })
+            .collect_vec();
+        // Mixes each key's vector with the others' as the key changes.
+        let change_keys = |per_key: Vec<VChords>| -> Vec<VChords> {
+            let total: VChords = per_key.iter().sum();
+            per_key
+                .iter()
+                .map(|v| v * (1.0 - key_change) + (total - v) * key_change / (NUM_KEYS - 1) as f32)
+                .collect()
+        };
+        let normalized = |mut per_key: Vec<VChords>| {
+            let total: f32 = per_key.iter().map(|v| v.sum()).sum();
+            per_key.iter_mut().for_each(|v| *v /= total);
+            per_key
+        };
+
+        let mut forwards: Vec<Vec<VChords>> = Vec::with_capacity(observations.len());
+        for emission in &emissions {
+            let forward = match forwards.last() {
+                None => params
+                    .log_initial
+                    .iter()
+                    .map(|i| i.map(f32::exp).component_mul(emission))
+                    .collect(),
+                Some(previous) => change_keys(previous.clone())
+                    .iter()
+                    .zip(&params.transitions)
+                    .map(|(p, t)| (t.transpose() * p).component_mul(emission))
+                    .collect(),
+            };
+            forwards.push(normalized(forward));
+        }
+        let mut posteriors = vec![VChords::zeros(); observations.len()];
+        let mut backward = vec![VChords::repeat(1.0); NUM_KEYS];
+        for t in (0..observations.len()).rev() {
+            let gamma: VChords = forwards[t]
+                .iter()
+                .zip(&backward)
+                .map(|(f, b)| f.component_mul(b))
+                .sum();
+            posteriors[t] = gamma / gamma.sum();
+            let next = backward
+                .iter()
+                .zip(&params.transitions)
+                .map(|(b, transition)| transition * b.component_mul(&emissions[t]))
+                .collect();
+            backward = normalized(change_keys(next));
+        }
+        posteriors
+    }
+    /// Commits every remaining observation and starts a new trellis, e.g. after silence.
+    pub(crate) fn reset(&mut self) {
+        if let Some(column) = self.column.take() {
+            let path = backtrack(&column, &self.uncommitted.iter().collect_vec());
+            self.history.extend(path);
+            self.uncommitted.clear();
+        }
+    }
+    /// Commits every remaining observation and returns the whole session.
+    pub(crate) fn finish(mut self) -> Vec<State> {
+        self.reset();
+        self.history
+    }
+    fn step(&mut self, model: &Model, context: &[Observation]) -> (Column, Pointers) {
+        let params = JointParams::cached(&mut self.params, model, self.key_change);
+        let emission = model.log_emissions(context);
+        match &self.column {
+            None => (
+                Column::initial(params, &emission),
+                vec![[0; NUM_CHORDS]; NUM_KEYS],
+            ),
+            Some(column) => column.next(params, &emission),
+        }
+    }
+}
+
+/// Decodes a whole recording at once, with unlimited lag.
+pub(crate) fn decode(model: &Model, observations: &[Observation], key_change: f32) -> Vec<State> {
+    let mut decoder = Decoder::new(key_change, usize::MAX);
+    for t in 0..observations.len() {
+        decoder.push(model, &observations[..=t]);
+    }
+    decoder.finish()
+}
+
+#[test]
+fn streaming_matches_offline() {
+    use crate::model::template_observation;
+    use crate::progression::parse_plain;
+    let model = Model::default();
+    let observations = parse_plain(&"C C G G Am Am F F ".repeat(5))
+        .into_iter()
+        .map(template_observation)
+        .collect_vec();
+    let offline = decode(&model, &observations, 0.005);
+    let mut decoder = Decoder::new(0.005, 4);
+    for t in 0..observations.len() {
+        decoder.push(&model, &observations[..=t]);
+    }
+    assert_eq!(decoder.history.len(), observations.len() - 4);
+    let streamed = decoder.finish();
+    assert_eq!(streamed.len(), 40);
+    // Keys can be committed before the evidence for them arrives, but chords should agree.
+    let chords = |states: &[State]| states.iter().map(|s| s.chord).collect_vec();
+    assert_eq!(chords(&streamed), chords(&offline));
+}
+
+#[test]
+fn posteriors_are_confident_on_clean_chords() {
+    use crate::model::template_observation;
+    let model = Model::default();
+    let observations = [0, 0, 14, 14].map(template_observation);
+    let mut decoder = Decoder::new(0.005, 4);
+    let posteriors = decoder.posteriors(&model, &observations);
+    // Adapting the model's transitions rebuilds the cached ones.
+    let mut adapted = Model::default();
+    adapted.enable_adaptation(0.9, 20.0);
+    adapted.adapt(&observations);
+    let before = decoder.params.as_ref().unwrap().transitions[0];
+    decoder.posteriors(&adapted, &observations);
+    assert_ne!(decoder.params.as_ref().unwrap().transitions[0], before);
+    for (posterior, chord) in posteriors.iter().zip([0, 0, 14, 14]) {
+        assert!((posterior.sum() - 1.0).abs() < 1e-4);
+        assert_eq!(posterior.argmax().0, chord);
+    }
+}
+
+#[test]
+fn recovers_from_an_off_model_first_observation() {
+    use crate::model::template_observation;
+    let model = Model::default();
+    let mut observations = vec![Observation::repeat(100.0)];
+    assert!(model.log_emissions(&observations).max() < -200.0);
+    observations.extend([0, 0, 14, 14].map(template_observation));
+    let mut decoder = Decoder::new(0.005, 4);
+    for t in 0..observations.len() - 1 {
+        decoder.push(&model, &observations[..=t]);
+    }
+    let inference = decoder.peek(&model, &observations, 2);
+    assert!(inference.key_confidence.is_finite());
+    assert_eq!(inference.chord_posterior.argmax().0, 14);
+    assert_eq!(inference.chords, [num_to_chord(14), num_to_chord(14)]);
+}
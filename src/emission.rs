@@ -0,0 +1,200 @@
+use std::path::Path;
+
+use chords::Chord;
+use clap::ValueEnum;
+use itertools::Itertools;
+use nalgebra::{DMatrix, DVector};
+use num::ToPrimitive;
+use serde::Deserialize;
+
+use crate::model::{
+    log_sum_exp, num_to_chord, Model, ModelParams, Observation, VChords, NUM_CHORDS,
+};
+
+/// Scores how well each chord explains an observation.
+pub(crate) trait Emission: Send {
+    /// Log emission score of every chord for the last observation of `context`. Earlier entries
+    /// are the preceding observations, oldest first, for scorers that look at more than one.
+    fn log_emissions(&self, context: &[Observation]) -> VChords;
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum EmissionKind {
+    /// Gaussian (mixture) per chord quality, from the model file
+    Gaussian,
+    /// Cosine similarity to binary chord templates; needs no training data
+    Template,
+    /// Small neural network over recent observations, with weights from --mlp-weights
+    Mlp,
+}
+
+/// A model with transitions from `params` and the chosen emission scorer, or why the scorer
+/// cannot be built.
+pub(crate) fn build_model(
+    params: &ModelParams,
+    kind: EmissionKind,
+    mlp_weights: Option<&Path>,
+) -> Result<Model, String> {
+    Ok(match kind {
+        EmissionKind::Gaussian => {
+            params.validate()?;
+            Model::new(params)
+        }
+        EmissionKind::Template => Model::with_emission(params, Box::new(Template::new())),
+        EmissionKind::Mlp => {
+            let path = mlp_weights.ok_or("The MLP scorer needs --mlp-weights")?;
+            Model::with_emission(params, Box::new(Mlp::load(path)?))
+        }
+    })
+}
+
+/// How many nats a cosine similarity of 1 is worth over one of 0.
+const TEMPLATE_SHARPNESS: f32 = 10.0;
+
+/// Scores chords by cosine similarity between the observation and the chord's notes. Silence
+/// scores every chord alike.
+pub(crate) struct Template {
+    templates: [Observation; NUM_CHORDS],
+}
+impl Template {
+    pub(crate) fn new() -> Self {
+        Self {
+            templates: (0..NUM_CHORDS)
+                .map(|n| template(&num_to_chord(n)))
+                .collect_vec()
+                .try_into()
+                .unwrap(),
+        }
+    }
+}
+impl Emission for Template {
+    fn log_emissions(&self, context: &[Observation]) -> VChords {
+        let Some(observation) = context.last().unwrap().try_normalize(f32::EPSILON) else {
+            return VChords::repeat(-(NUM_CHORDS as f32).ln());
+        };
+        VChords::from_fn(|i, _| TEMPLATE_SHARPNESS * self.templates[i].dot(&observation))
+    }
+}
+
+fn template(chord: &Chord) -> Observation {
+    let mut observation = Observation::zeros();
+    for note in chord.notes() {
+        observation[note.to_usize().unwrap()] = 1.0;
+    }
+    observation.normalize()
+}
+
+#[derive(Deserialize)]
+struct LayerParams {
+    /// One row per output.
+    weights: Vec<Vec<f32>>,
+    bias: Vec<f32>,
+}
+
+/// Weights of an `Mlp`, as stored in a weights file.
+#[derive(Deserialize)]
+struct MlpParams {
+    /// How many observations, ending with the scored one, make up the input.
+    context: usize,
+    layers: Vec<LayerParams>,
+}
+
+/// A multilayer perceptron over the concatenated chroma of the last `context` observations, with
+/// ReLU hidden layers and a softmax over chords. Missing history is zero-filled.
+pub(crate) struct Mlp {
+    context: usize,
+    layers: Vec<(DMatrix<f32>, DVector<f32>)>,
+}
+impl Mlp {
+    pub(crate) fn load(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read MLP weights {}: {e}", path.display()))?;
+        let params: MlpParams = serde_json::from_str(&contents)
+            .map_err(|e| format!("Failed to parse MLP weights {}: {e}", path.display()))?;
+        Self::new(params)
+    }
+    fn new(params: MlpParams) -> Result<Self, String> {
+        if params.context == 0 || params.layers.is_empty() {
+            return Err("MLP needs a context and at least one layer".to_string());
+        }
+        let note_count = Observation::zeros().len();
+        let mut inputs = params.context * note_count;
+        let mut layers = vec![];
+        for (i, layer) in params.layers.into_iter().enumerate() {
+            let outputs = layer.weights.len();
+            if outputs == 0 || layer.weights.iter().any(|row| row.len() != inputs) {
+                return Err(format!("MLP layer {i} must have rows of {inputs} inputs"));
+            }
+            if layer.bias.len() != outputs {
+                return Err(format!("MLP layer {i} must have {outputs} biases"));
+            }
+            let weights =
+                DMatrix::from_row_iterator(outputs, inputs, layer.weights.into_iter().flatten());
+            inputs = outputs;
+            layers.push((weights, DVector::from_vec(layer.bias)));
+        }
+        if inputs != NUM_CHORDS {
+            return Err(format!("MLP must output one value per chord, not {inputs}"));
+        }
+        Ok(Self {
+            context: params.context,
+            layers,
+        })
+    }
+}
+impl Emission for Mlp {
+    fn log_emissions(&self, context: &[Observation]) -> VChords {
+        let history = &context[context.len().saturating_sub(self.context)..];
+        let mut activation = DVector::zeros(self.layers[0].0.ncols());
+        let offset = activation.len() - history.len() * Observation::zeros().len();
+        for (i, &value) in history.iter().flat_map(|o| o.iter()).enumerate() {
+            activation[offset + i] = value;
+        }
+        for (i, (weights, bias)) in self.layers.iter().enumerate() {
+            activation = weights * activation + bias;
+            if i + 1 < self.layers.len() {
+                activation.apply(|a| *a = a.max(0.0));
+            }
+        }
+        let norm = log_sum_exp(activation.iter().copied());
+        VChords::from_iterator(activation.iter().map(|a| a - norm))
+    }
+}
+
+#[test]
+fn template_and_mlp_pick_clean_chords() {
+    use crate::model::template_observation;
+    // A single linear layer whose rows are the chord templates scores like `Template`.
+    let mlp = Mlp::new(MlpParams {
+        context: 2,
+        layers: vec![LayerParams {
+            weights: (0..NUM_CHORDS)
+                .map(|n| {
+                    let template = template(&num_to_chord(n)) * TEMPLATE_SHARPNESS;
+                    vec![0.0; 12]
+                        .into_iter()
+                        .chain(template.iter().copied())
+                        .collect()
+                })
+                .collect(),
+            bias: vec![0.0; NUM_CHORDS],
+        }],
+    })
+    .unwrap();
+    let template = Template::new();
+    for chord in [0, 9, 14, 19] {
+        let observation = template_observation(chord);
+        assert_eq!(template.log_emissions(&[observation]).argmax().0, chord);
+        let scores = mlp.log_emissions(&[observation]);
+        assert_eq!(scores.argmax().0, chord);
+        assert!((log_sum_exp(scores.iter().copied())).abs() < 1e-4);
+    }
+    // Silence favours no chord, and a network without layers is refused.
+    let silence = template.log_emissions(&[Observation::zeros()]);
+    assert!(silence.iter().all(|&s| s == silence[0] && s.is_finite()));
+    assert!(Mlp::new(MlpParams {
+        context: 1,
+        layers: vec![]
+    })
+    .is_err());
+}
@@ -0,0 +1,109 @@
+use std::path::Path;
+
+use itertools::Itertools;
+
+use crate::capture::Samples;
+use crate::emission::{build_model, EmissionKind};
+use crate::model::{rotate_to_root, Model, ModelParams, Observation, NUM_CHORDS};
+use crate::train::fit_mixture;
+
+/// Every `HOLD_OUT`th sample of each quality is kept out of training and used for testing.
+const HOLD_OUT: usize = 5;
+
+/// Fraction of `samples` classified correctly by their best emission score, over all twelve
+/// transpositions of each sample. Samples are scored alone, so context-window scorers see no
+/// history.
+fn accuracy(model: &Model, samples: &[Vec<f32>], minor: bool) -> f32 {
+    let roots = NUM_CHORDS / 2;
+    let mut correct = 0;
+    for sample in samples {
+        let sample = Observation::from_column_slice(sample);
+        for root in 0..roots {
+            let observation = rotate_to_root(&sample, (roots - root) % roots);
+            if model.log_emissions(&[observation]).argmax().0 == root * 2 + minor as usize {
+                correct += 1;
+            }
+        }
+    }
+    correct as f32 / (samples.len() * roots) as f32
+}
+
+/// Compares emission scorers on held out captured samples: the single Gaussian baseline,
+/// `components`-component mixtures, templates and, given weights, the MLP.
+pub(crate) fn evaluate(
+    samples_dir: &Path,
+    base: ModelParams,
+    components: usize,
+    mlp_weights: Option<&Path>,
+) {
+    let samples = Samples::load(samples_dir);
+    let split = |samples: &[Vec<f32>]| -> (Vec<Vec<f32>>, Vec<Vec<f32>>) {
+        samples
+            .iter()
+            .cloned()
+            .enumerate()
+            .partition_map(|(i, sample)| match i % HOLD_OUT {
+                0 => itertools::Either::Right(sample),
+                _ => itertools::Either::Left(sample),
+            })
+    };
+    let (major_train, major_test) = split(&samples.major);
+    let (minor_train, minor_test) = split(&samples.minor);
+    if major_test.is_empty() || minor_test.is_empty() {
+        println!(
+            "Not enough samples in {} to evaluate",
+            samples_dir.display()
+        );
+        return;
+    }
+    println!(
+        "Training on {} major and {} minor samples, testing on {} and {}",
+        major_train.len(),
+        minor_train.len(),
+        major_test.len(),
+        minor_test.len()
+    );
+
+    let mut models = vec![];
+    for k in [1, components] {
+        let mut params = base.clone();
+        for (name, train, mixture) in [
+            ("major", &major_train, &mut params.major),
+            ("minor", &minor_train, &mut params.minor),
+        ] {
+            match fit_mixture(train, k) {
+                Some(fitted) => *mixture = fitted,
+                None => println!("Too few {name} samples for {k} components, using the base model"),
+            }
+        }
+        models.push((format!("{k}-component Gaussian"), Model::new(&params)));
+    }
+    for (name, kind) in [
+        ("template", EmissionKind::Template),
+        ("MLP", EmissionKind::Mlp),
+    ] {
+        if kind == EmissionKind::Mlp && mlp_weights.is_none() {
+            continue;
+        }
+        match build_model(&base, kind, mlp_weights) {
+            Ok(model) => models.push((name.to_string(), model)),
+            Err(e) => println!("Skipping the {name} scorer: {e}"),
+        }
+    }
+
+    let mut baseline = None;
+    for (name, model) in &models {
+        let major = accuracy(model, &major_test, false);
+        let minor = accuracy(model, &minor_test, true);
+        let overall = (major * major_test.len() as f32 + minor * minor_test.len() as f32)
+            / (major_test.len() + minor_test.len()) as f32;
+        let baseline = *baseline.get_or_insert(overall);
+        println!(
+            "{name:>22}: major {:5.1}%  minor {:5.1}%  overall {:5.1}%  ({:+.1} points)",
+            major * 100.0,
+            minor * 100.0,
+            overall * 100.0,
+            (overall - baseline) * 100.0
+        );
+    }
+}
@@ -0,0 +1,97 @@
+use itertools::Itertools;
+use textplots::{Chart, Plot, Shape};
+
+use crate::model::{chord_name, GaussianParams, Model, ModelParams, NOTE_NAMES, NUM_CHORDS};
+
+/// How many successors are listed per chord.
+const SUCCESSORS: usize = 3;
+
+/// Prints a human readable summary of a model file.
+pub(crate) fn inspect(params: &ModelParams) {
+    let model = Model::new(params);
+    let transition = model.log_transition().map(f32::exp);
+    let initial = model.log_initial().map(f32::exp);
+
+    println!("Vocabulary ({NUM_CHORDS} chords):");
+    println!("  {}", (0..NUM_CHORDS).map(chord_name).join(" "));
+    println!(
+        "Transitions: {}",
+        if params.transitions.is_some() {
+            "learned"
+        } else {
+            "built-in default"
+        }
+    );
+
+    println!("\nTransition probabilities (%), from row to column:");
+    print!("{:>6}", "");
+    for to in 0..NUM_CHORDS {
+        print!("{:>5}", chord_name(to));
+    }
+    println!();
+    for from in 0..NUM_CHORDS {
+        print!("{:>6}", chord_name(from));
+        for to in 0..NUM_CHORDS {
+            print!("{:>5.1}", transition[(from, to)] * 100.0);
+        }
+        println!();
+    }
+
+    println!("\nMost likely successors (initial probability, stay):");
+    for from in 0..NUM_CHORDS {
+        let successors = (0..NUM_CHORDS)
+            .filter(|&to| to != from)
+            .sorted_by(|&a, &b| transition[(from, b)].total_cmp(&transition[(from, a)]))
+            .take(SUCCESSORS)
+            .map(|to| format!("{} {:.1}%", chord_name(to), transition[(from, to)] * 100.0))
+            .join(", ");
+        println!(
+            "{:>6} ({:4.1}%, {:4.1}%): {successors}",
+            chord_name(from),
+            initial[from] * 100.0,
+            transition[(from, from)] * 100.0,
+        );
+    }
+
+    for (name, mixture) in [("major", &params.major), ("minor", &params.minor)] {
+        println!("\n{name}: {} component(s)", mixture.len());
+        for (i, gaussian) in mixture.iter().enumerate() {
+            print_component(i, gaussian);
+        }
+    }
+}
+
+fn print_component(i: usize, gaussian: &GaussianParams) {
+    let eigenvalues = gaussian
+        .cov()
+        .symmetric_eigenvalues()
+        .iter()
+        .copied()
+        .sorted_by(|a, b| b.total_cmp(a))
+        .collect_vec();
+    let condition = eigenvalues[0] / eigenvalues[eigenvalues.len() - 1];
+    println!("  component {i}, weight {:.3}", gaussian.weight);
+    println!(
+        "    covariance eigenvalues: {}",
+        eigenvalues.iter().map(|e| format!("{e:.2e}")).join(" ")
+    );
+    println!("    condition number: {condition:.3e}");
+    let points = gaussian
+        .mean
+        .iter()
+        .enumerate()
+        .map(|(note, &value)| (note as f32, value))
+        .collect_vec();
+    println!("    mean chroma, rotated to C:");
+    Chart::new(120, 40, 0.0, (NOTE_NAMES.len() - 1) as f32)
+        .lineplot(&Shape::Steps(&points))
+        .display();
+    println!(
+        "    {}",
+        NOTE_NAMES
+            .iter()
+            .zip(&gaussian.mean)
+            .map(|(name, value)| format!("{name} {value:.2}"))
+            .join("  ")
+    );
+}
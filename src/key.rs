@@ -0,0 +1,537 @@
+use std::str::FromStr;
+
+use chords::{Chord, Note};
+use num::ToPrimitive;
+use serde::Serialize;
+
+use crate::model::{MChords, Model, Observation, VChords, NOTE_NAMES, NUM_CHORDS};
+use crate::progression::parse_chord_symbol;
+
+/// Keys are indexed like chords: `root * 2` for major, `root * 2 + 1` for minor.
+pub(crate) const NUM_KEYS: usize = NUM_CHORDS;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Mode {
+    Major,
+    Minor,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Key {
+    pub(crate) root: Note,
+    pub(crate) mode: Mode,
+}
+impl Key {
+    pub(crate) fn from_index(index: usize) -> Self {
+        Self {
+            root: ((index / 2) as u8).into(),
+            mode: match index % 2 {
+                0 => Mode::Major,
+                _ => Mode::Minor,
+            },
+        }
+    }
+    pub(crate) fn index(&self) -> usize {
+        self.root.to_usize().unwrap() * 2 + (self.mode == Mode::Minor) as usize
+    }
+    /// How typical `chord` is in this key, by harmonic function.
+    pub(crate) fn chord_weight(&self, chord: usize) -> f32 {
+        let offset = (chord / 2 + 12 - self.root.to_usize().unwrap()) % 12;
+        let minor = chord % 2 == 1;
+        let table: &[(usize, bool, f32)] = match self.mode {
+            Mode::Major => &[
+                (0, false, 1.0),
+                (5, false, 0.8),
+                (7, false, 0.8),
+                (9, true, 0.6),
+                (2, true, 0.5),
+                (4, true, 0.4),
+                (10, false, 0.15),
+                (2, false, 0.15),
+                (4, false, 0.1),
+                (5, true, 0.1),
+            ],
+            Mode::Minor => &[
+                (0, true, 1.0),
+                (5, true, 0.7),
+                (7, false, 0.7),
+                (8, false, 0.6),
+                (10, false, 0.6),
+                (3, false, 0.6),
+                (7, true, 0.3),
+                (5, false, 0.1),
+                (0, false, 0.1),
+            ],
+        };
+        table
+            .iter()
+            .find(|&&(o, m, _)| o == offset && m == minor)
+            .map_or(0.02, |&(_, _, weight)| weight)
+    }
+}
+
+/// Temperley's (2007) key profile for major keys from the Kostka-Payne corpus: the share of
+/// segments in which each pitch class above the tonic sounds.
+const MAJOR_PROFILE: [f32; 12] = [
+    0.748, 0.060, 0.488, 0.082, 0.670, 0.460, 0.096, 0.715, 0.104, 0.366, 0.057, 0.400,
+];
+/// The same for minor keys, whose leading tone is mostly raised as in harmonic minor.
+const MINOR_PROFILE: [f32; 12] = [
+    0.712, 0.084, 0.474, 0.618, 0.049, 0.460, 0.105, 0.747, 0.404, 0.067, 0.133, 0.330,
+];
+
+/// The modes the key estimator tells apart.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ScaleMode {
+    Ionian,
+    Dorian,
+    Phrygian,
+    Lydian,
+    Mixolydian,
+    Aeolian,
+    HarmonicMinor,
+    MelodicMinor,
+}
+impl ScaleMode {
+    pub(crate) const ALL: [ScaleMode; 8] = [
+        ScaleMode::Ionian,
+        ScaleMode::Dorian,
+        ScaleMode::Phrygian,
+        ScaleMode::Lydian,
+        ScaleMode::Mixolydian,
+        ScaleMode::Aeolian,
+        ScaleMode::HarmonicMinor,
+        ScaleMode::MelodicMinor,
+    ];
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            ScaleMode::Ionian => "ionian",
+            ScaleMode::Dorian => "dorian",
+            ScaleMode::Phrygian => "phrygian",
+            ScaleMode::Lydian => "lydian",
+            ScaleMode::Mixolydian => "mixolydian",
+            ScaleMode::Aeolian => "aeolian",
+            ScaleMode::HarmonicMinor => "harmonic-minor",
+            ScaleMode::MelodicMinor => "melodic-minor",
+        }
+    }
+    /// Semitones above the tonic of each scale degree.
+    pub(crate) fn intervals(&self) -> [usize; 7] {
+        match self {
+            ScaleMode::Ionian => [0, 2, 4, 5, 7, 9, 11],
+            ScaleMode::Dorian => [0, 2, 3, 5, 7, 9, 10],
+            ScaleMode::Phrygian => [0, 1, 3, 5, 7, 8, 10],
+            ScaleMode::Lydian => [0, 2, 4, 6, 7, 9, 11],
+            ScaleMode::Mixolydian => [0, 2, 4, 5, 7, 9, 10],
+            ScaleMode::Aeolian => [0, 2, 3, 5, 7, 8, 10],
+            ScaleMode::HarmonicMinor => [0, 2, 3, 5, 7, 8, 11],
+            ScaleMode::MelodicMinor => [0, 2, 3, 5, 7, 9, 11],
+        }
+    }
+    /// Temperley's profile of the major or minor key with the mode's third, with the weight of each
+    /// degree the mode alters swapped with that of the note it replaces.
+    fn profile(&self) -> Observation {
+        let intervals = self.intervals();
+        let (mut profile, base) = if intervals[2] == 4 {
+            (MAJOR_PROFILE, ScaleMode::Ionian.intervals())
+        } else {
+            (MINOR_PROFILE, ScaleMode::HarmonicMinor.intervals())
+        };
+        for (&from, &to) in base.iter().zip(&intervals) {
+            profile.swap(from, to);
+        }
+        Observation::from_row_slice(&profile)
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ModalKey {
+    pub(crate) root: Note,
+    pub(crate) mode: ScaleMode,
+}
+impl ModalKey {
+    pub(crate) fn name(&self) -> String {
+        format!(
+            "{} {:?}",
+            NOTE_NAMES[self.root.to_usize().unwrap()],
+            self.mode
+        )
+    }
+    /// The tonic of the parent major key; harmonic and melodic minor use their relative major.
+    fn parent_root(&self) -> u8 {
+        let offset = match self.mode {
+            ScaleMode::Ionian => 0,
+            ScaleMode::Dorian => 2,
+            ScaleMode::Phrygian => 4,
+            ScaleMode::Lydian => 5,
+            ScaleMode::Mixolydian => 7,
+            ScaleMode::Aeolian | ScaleMode::HarmonicMinor | ScaleMode::MelodicMinor => 9,
+        };
+        (self.root.to_u8().unwrap() + 12 - offset) % 12
+    }
+}
+
+/// Parses a chord-like tonic with an optional mode, e.g. "D", "Am" or "D dorian".
+impl FromStr for ModalKey {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tokens = s.split_whitespace();
+        let tonic = tokens
+            .next()
+            .and_then(parse_chord_symbol)
+            .ok_or_else(|| format!("Invalid key '{s}'"))?;
+        let mode = match tokens.next() {
+            None if tonic % 2 == 1 => ScaleMode::Aeolian,
+            None => ScaleMode::Ionian,
+            Some(name) => *ScaleMode::ALL
+                .iter()
+                .find(|mode| mode.name().eq_ignore_ascii_case(name))
+                .ok_or_else(|| format!("Unknown mode '{name}'"))?,
+        };
+        Ok(Self {
+            root: ((tonic / 2) as u8).into(),
+            mode,
+        })
+    }
+}
+
+/// How a key change relates the two keys.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Modulation {
+    /// The same mode on another tonic, `semitones` up (negative for down), e.g. 2 for the last
+    /// chorus going up a step.
+    Transposition {
+        semitones: i8,
+    },
+    /// The same notes on another tonic, e.g. C major to A minor.
+    Relative,
+    /// The same tonic in another mode, e.g. C major to C minor.
+    Parallel,
+    Other,
+}
+impl Modulation {
+    fn between(from: &ModalKey, to: &ModalKey) -> Self {
+        let up = (to.root.to_i8().unwrap() - from.root.to_i8().unwrap()).rem_euclid(12);
+        if from.mode == to.mode {
+            Modulation::Transposition {
+                semitones: if up > 6 { up - 12 } else { up },
+            }
+        } else if from.root == to.root {
+            Modulation::Parallel
+        } else if from.parent_root() == to.parent_root() {
+            Modulation::Relative
+        } else {
+            Modulation::Other
+        }
+    }
+}
+
+/// A stretch of beats, `start..end`, in one key.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct KeyRegion {
+    pub(crate) key: ModalKey,
+    pub(crate) start: usize,
+    pub(crate) end: usize,
+}
+
+#[derive(Serialize, Debug, Clone, Copy)]
+pub(crate) struct KeyChange {
+    pub(crate) from: ModalKey,
+    pub(crate) to: ModalKey,
+    /// The beat the new key region starts at.
+    pub(crate) beat: usize,
+    pub(crate) modulation: Modulation,
+}
+
+#[derive(Serialize, Debug, Clone, Copy)]
+pub(crate) struct KeyCandidate {
+    pub(crate) key: ModalKey,
+    /// Correlation between the accumulated chroma and the key's profile.
+    pub(crate) score: f32,
+}
+
+/// The estimated key and its strongest competitors, best first.
+#[derive(Serialize, Debug, Clone)]
+pub(crate) struct ScaleEstimate {
+    pub(crate) key: ModalKey,
+    pub(crate) candidates: Vec<KeyCandidate>,
+}
+
+/// Krumhansl-Schmuckler style key finding: correlates chroma accumulated over roughly `horizon`
+/// observations with the profile of every mode on every root. The estimate only moves to another
+/// key once it correlates `hysteresis` better than the current one, dated back to the beat that
+/// key first came out on top.
+///
+/// Key regions only settle once their key has held for the whole horizon, so that the estimate
+/// passing through neighbouring keys during a modulation is not mistaken for several.
+pub(crate) struct KeyEstimator {
+    decay: f32,
+    hysteresis: f32,
+    settle: usize,
+    chroma: Observation,
+    /// The current estimate and the beat it took over, before being dated back.
+    current: Option<(ModalKey, usize)>,
+    /// A key other than the current one that has been the best candidate since the given beat.
+    challenger: Option<(ModalKey, usize)>,
+    /// Settled key regions; the estimate may have moved on from the last one.
+    regions: Vec<KeyRegion>,
+    beats: usize,
+}
+impl KeyEstimator {
+    pub(crate) fn new(horizon: f32, hysteresis: f32) -> Self {
+        Self {
+            decay: (-1.0 / horizon).exp(),
+            hysteresis,
+            settle: horizon.round() as usize,
+            chroma: Observation::zeros(),
+            current: None,
+            challenger: None,
+            regions: vec![],
+            beats: 0,
+        }
+    }
+    /// Adds a beat, returning the key change it settles, if any.
+    pub(crate) fn push(&mut self, observation: &Observation) -> Option<KeyChange> {
+        self.chroma = self.chroma * self.decay + observation;
+        let beat = self.beats;
+        self.beats += 1;
+        let candidates = self.candidates();
+        let best = candidates[0];
+        let (current, since) = match self.current {
+            None => (best.key, beat),
+            Some((current, since)) => {
+                if best.key == current {
+                    self.challenger = None;
+                } else if self.challenger.map(|(key, _)| key) != Some(best.key) {
+                    self.challenger = Some((best.key, beat));
+                }
+                let current_score = candidates.iter().find(|c| c.key == current).unwrap().score;
+                if best.key == current || best.score - current_score < self.hysteresis {
+                    (current, since)
+                } else {
+                    let start = self.challenger.take().map_or(beat, |(_, start)| start);
+                    // Leaving the settled key: its region ends where the new key came out on top.
+                    if let Some(settled) = self.regions.last_mut().filter(|r| r.key == current) {
+                        settled.end = start;
+                    }
+                    (best.key, beat)
+                }
+            }
+        };
+        self.current = Some((current, since));
+
+        let settled = self.regions.last().map(|r| r.key);
+        if settled == Some(current) {
+            self.regions.last_mut().unwrap().end = beat + 1;
+            return None;
+        }
+        if beat + 1 - since < self.settle {
+            return None;
+        }
+        let start = self.regions.last().map_or(0, |r| r.end);
+        self.regions.push(KeyRegion {
+            key: current,
+            start,
+            end: beat + 1,
+        });
+        let from = settled?;
+        Some(KeyChange {
+            from,
+            to: current,
+            beat: start,
+            modulation: Modulation::between(&from, &current),
+        })
+    }
+    /// The key regions so far, in order.
+    pub(crate) fn regions(&self) -> &[KeyRegion] {
+        &self.regions
+    }
+    /// The current key and the `count` best candidates.
+    pub(crate) fn estimate(&self, count: usize) -> ScaleEstimate {
+        let candidates = self.candidates();
+        ScaleEstimate {
+            key: self.current.map_or(candidates[0].key, |(key, _)| key),
+            candidates: candidates.into_iter().take(count).collect(),
+        }
+    }
+    fn candidates(&self) -> Vec<KeyCandidate> {
+        let mut candidates = ScaleMode::ALL
+            .iter()
+            .flat_map(|&mode| {
+                let profile = mode.profile();
+                (0..12).map(move |root| {
+                    let rotated = Observation::from_fn(|i, _| profile[(i + 12 - root) % 12]);
+                    KeyCandidate {
+                        key: ModalKey {
+                            root: (root as u8).into(),
+                            mode,
+                        },
+                        score: correlation(&self.chroma, &rotated),
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        candidates.sort_by(|a, b| b.score.total_cmp(&a.score));
+        candidates
+    }
+}
+
+fn correlation(a: &Observation, b: &Observation) -> f32 {
+    let a = a.add_scalar(-a.mean());
+    let b = b.add_scalar(-b.mean());
+    let norm = a.norm() * b.norm();
+    if norm == 0.0 {
+        0.0
+    } else {
+        a.dot(&b) / norm
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct KeyInference {
+    pub(crate) chords: Vec<Chord>,
+    /// The key each of `chords` was decoded in.
+    pub(crate) keys: Vec<Key>,
+    pub(crate) key: Key,
+    /// Filtered posterior probability of `key` at the last observation.
+    pub(crate) key_confidence: f32,
+    /// Filtered posterior over chords at the last observation, marginalized over keys.
+    pub(crate) chord_posterior: VChords,
+}
+
+/// The chord transition matrix within `key`, in log space.
+pub(crate) fn key_transition(log_transition: &MChords, key: &Key) -> MChords {
+    let mut transition = MChords::from_fn(|c, c_next| {
+        let weight = if c == c_next {
+            1.0
+        } else {
+            key.chord_weight(c_next)
+        };
+        log_transition[(c, c_next)].exp() * weight
+    });
+    for mut row in transition.row_iter_mut() {
+        row /= row.sum();
+    }
+    transition.map(f32::ln)
+}
+
+/// The model's chord transitions within each key, kept until the model's transitions change,
+/// e.g. as it adapts.
+pub(crate) struct KeyTransitions {
+    source: MChords,
+    by_key: Vec<MChords>,
+}
+impl KeyTransitions {
+    pub(crate) fn new(model: &Model) -> Self {
+        Self {
+            source: *model.log_transition(),
+            by_key: (0..NUM_KEYS)
+                .map(|k| key_transition(model.log_transition(), &Key::from_index(k)))
+                .collect(),
+        }
+    }
+    /// Rebuilds the transitions if `model`'s have changed since, returning whether they had.
+    pub(crate) fn update(&mut self, model: &Model) -> bool {
+        let changed = *model.log_transition() != self.source;
+        if changed {
+            *self = Self::new(model);
+        }
+        changed
+    }
+    pub(crate) fn in_key(&self, key: usize) -> &MChords {
+        &self.by_key[key]
+    }
+}
+
+#[test]
+fn detects_relative_minor() {
+    use crate::decoder::Decoder;
+    use crate::model::template_observation;
+    use crate::progression::parse_plain;
+    use itertools::Itertools;
+    let model = Model::default();
+    for (progression, expected) in [
+        ("Am Am Dm Dm E E Am Am", Key::from_index(19)),
+        ("G G C C D D G G Em Em", Key::from_index(14)),
+    ] {
+        let observations = parse_plain(progression)
+            .into_iter()
+            .map(template_observation)
+            .collect_vec();
+        let mut decoder = Decoder::new(0.005, usize::MAX);
+        for t in 1..observations.len() {
+            decoder.push(&model, &observations[..t]);
+        }
+        let inference = decoder.peek(&model, &observations, usize::MAX);
+        assert_eq!(inference.key, expected);
+        assert!(inference.key_confidence > 0.3);
+    }
+}
+
+#[test]
+fn estimates_modes_with_hysteresis() {
+    use crate::model::template_observation;
+    use crate::progression::parse_plain;
+    let estimate = |progression: &str| {
+        let mut estimator = KeyEstimator::new(16.0, 0.05);
+        for chord in parse_plain(progression) {
+            estimator.push(&template_observation(chord));
+        }
+        estimator.estimate(3)
+    };
+    let dorian = estimate("Dm Dm G Dm Dm Dm G Dm");
+    assert_eq!(dorian.key.mode, ScaleMode::Dorian);
+    assert_eq!(dorian.key.root.to_u8(), Some(2));
+    assert_eq!(dorian.candidates.len(), 3);
+    assert!(dorian.candidates[0].score >= dorian.candidates[1].score);
+    let harmonic = estimate("Am Dm E Am Am Dm E Am");
+    assert_eq!(harmonic.key.mode, ScaleMode::HarmonicMinor);
+
+    // A single beat of a foreign chord does not move the estimate.
+    let mut estimator = KeyEstimator::new(16.0, 0.05);
+    for chord in parse_plain("C F G C C F G C") {
+        estimator.push(&template_observation(chord));
+    }
+    let before = estimator.estimate(1).key;
+    estimator.push(&template_observation(parse_plain("Bb")[0]));
+    assert_eq!(estimator.estimate(1).key, before);
+}
+
+#[test]
+fn tracks_modulations_as_regions() {
+    use crate::model::template_observation;
+    use crate::progression::parse_plain;
+    let run = |progression: &str| {
+        let mut estimator = KeyEstimator::new(16.0, 0.05);
+        let changes = parse_plain(progression)
+            .into_iter()
+            .filter_map(|chord| estimator.push(&template_observation(chord)))
+            .collect::<Vec<_>>();
+        (changes, estimator.regions().to_vec())
+    };
+    let verse = "C F G C Am F G C ".repeat(3);
+    let (changes, regions) = run(&(verse.clone() + &"D G A D Bm G A D ".repeat(7)));
+    assert_eq!(changes.len(), 1);
+    assert_eq!(
+        changes[0].modulation,
+        Modulation::Transposition { semitones: 2 }
+    );
+    assert_eq!(regions.len(), 2);
+    assert_eq!((regions[0].start, regions[1].end), (0, 80));
+    assert_eq!(regions[0].end, regions[1].start);
+    // Dated back to near the actual change rather than when the estimate gave way.
+    assert_eq!(changes[0].beat, regions[1].start);
+    assert!((24..32).contains(&regions[1].start));
+
+    let (changes, _) = run(&(verse.clone() + &"Am Dm Em Am Am F G Am ".repeat(5)));
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].modulation, Modulation::Relative);
+    assert_eq!(changes[0].to.mode, ScaleMode::Aeolian);
+
+    // Without hysteresis the estimate moves freely, but a key holding still settles.
+    let mut estimator = KeyEstimator::new(16.0, 0.0);
+    for chord in parse_plain(&verse) {
+        estimator.push(&template_observation(chord));
+    }
+    assert_eq!(estimator.regions().len(), 1);
+}
@@ -0,0 +1,125 @@
+use chords::Chord;
+use serde::{Deserialize, Serialize};
+
+use crate::key::ModalKey;
+use crate::model::num_to_chord;
+use crate::progression::parse_chord_symbol;
+
+/// An override that can be bound to a MIDI note or controller.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Trigger {
+    /// Locks the scale to the one currently in use.
+    LockScale,
+    /// Locks the chord to the one currently in use.
+    FreezeChord,
+    /// Locks the chord to the given one, e.g. "Am".
+    ForceChord(String),
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MidiControl {
+    Note(u8),
+    Controller(u8),
+}
+
+/// Manual overrides of the inferred chord and scale, shared with the web UI.
+#[derive(Serialize, Debug, Clone, Default)]
+pub(crate) struct Locks {
+    scale: Option<ModalKey>,
+    chord: Option<Chord>,
+    /// The trigger the next note or controller will be bound to.
+    learning: Option<Trigger>,
+    bindings: Vec<(MidiControl, Trigger)>,
+}
+impl Locks {
+    pub(crate) fn lock_scale(&mut self, scale: Option<ModalKey>) {
+        self.scale = scale;
+    }
+    pub(crate) fn lock_chord(&mut self, chord: Option<Chord>) {
+        self.chord = chord;
+    }
+    pub(crate) fn learn(&mut self, trigger: Option<Trigger>) {
+        self.learning = trigger;
+    }
+    /// The chord to map to: the locked one, if any.
+    pub(crate) fn chord<'a>(&'a self, inferred: &'a Chord) -> &'a Chord {
+        self.chord.as_ref().unwrap_or(inferred)
+    }
+    pub(crate) fn scale(&self, inferred: ModalKey) -> ModalKey {
+        self.scale.unwrap_or(inferred)
+    }
+    pub(crate) fn is_bound(&self, control: MidiControl) -> bool {
+        self.bindings.iter().any(|(c, _)| *c == control)
+    }
+    /// Handles a note or controller, binding it while learning. `engaged` is `None` for a note on,
+    /// which toggles, and the switch position for a controller. Returns whether the message was
+    /// consumed as a trigger (and the locks may have changed) rather than to be played.
+    pub(crate) fn midi(
+        &mut self,
+        control: MidiControl,
+        engaged: Option<bool>,
+        chord: &Chord,
+        scale: ModalKey,
+    ) -> bool {
+        if let Some(trigger) = self.learning.take() {
+            self.bindings.retain(|(c, _)| *c != control);
+            self.bindings.push((control, trigger));
+            return true;
+        }
+        let Some((_, trigger)) = self.bindings.iter().find(|(c, _)| *c == control) else {
+            return false;
+        };
+        match trigger {
+            Trigger::LockScale => {
+                let engage = engaged.unwrap_or(self.scale.is_none());
+                self.scale = engage.then_some(scale);
+            }
+            Trigger::FreezeChord => {
+                let engage = engaged.unwrap_or(self.chord.is_none());
+                self.chord = engage.then(|| chord.clone());
+            }
+            Trigger::ForceChord(label) => {
+                let engage = engaged.unwrap_or(self.chord.is_none());
+                self.chord = if engage {
+                    parse_chord_symbol(label).map(num_to_chord)
+                } else {
+                    None
+                };
+            }
+        }
+        true
+    }
+}
+
+#[test]
+fn learned_triggers_toggle_locks() {
+    use crate::model::chord_to_num;
+    let inferred: Chord = "G".parse().unwrap();
+    let scale: ModalKey = "D dorian".parse().unwrap();
+    let mut locks = Locks::default();
+    assert!(!locks.midi(MidiControl::Note(36), None, &inferred, scale));
+
+    locks.learn(Some(Trigger::FreezeChord));
+    assert!(locks.midi(MidiControl::Note(36), None, &inferred, scale));
+    assert!(locks.chord.is_none());
+    locks.learn(Some(Trigger::ForceChord("Am".into())));
+    assert!(locks.midi(MidiControl::Controller(64), Some(false), &inferred, scale));
+
+    // A note toggles the freeze.
+    locks.midi(MidiControl::Note(36), None, &inferred, scale);
+    let other: Chord = "C".parse().unwrap();
+    assert_eq!(chord_to_num(locks.chord(&other)), chord_to_num(&inferred));
+    locks.midi(MidiControl::Note(36), None, &inferred, scale);
+    assert_eq!(chord_to_num(locks.chord(&other)), chord_to_num(&other));
+
+    // A controller follows its switch position.
+    locks.midi(MidiControl::Controller(64), Some(true), &inferred, scale);
+    assert_eq!(chord_to_num(locks.chord(&other)), 19);
+    locks.midi(MidiControl::Controller(64), Some(true), &inferred, scale);
+    assert_eq!(chord_to_num(locks.chord(&other)), 19);
+    locks.midi(MidiControl::Controller(64), Some(false), &inferred, scale);
+    assert!(locks.chord.is_none());
+
+    locks.lock_scale(Some(scale));
+    assert_eq!(locks.scale("C".parse().unwrap()), scale);
+}
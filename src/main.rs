@@ -1,53 +1,155 @@
 #![feature(iter_collect_into, default_free_fn)]
 
+mod analysis;
+mod arpeggio;
+mod bass;
+mod capture;
+mod chart;
+mod chord_track;
+mod decoder;
+mod emission;
+mod evaluate;
+mod inspect;
+mod key;
+mod lock;
+mod mapping;
+mod midi;
 mod model;
+mod preset;
+mod progression;
+mod routing;
+mod semi_markov;
+mod tempo;
+mod train;
+mod voice;
+mod voicing;
 
+use arpeggio::{Arpeggiator, Pattern};
 use aubio::Onset;
-use chords::{Chord, Note, Scale, ScaleBuilder};
+use bass::{Bass, BassStyle};
+use chart::{read_chart, Follower};
+use chord_track::ChordTrack;
+use chords::Chord;
 use coremidi::{Client, Destination, Destinations, OutputPort, Protocol};
 use coremidi::{PacketBuffer, Sources};
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use cpal::{default_host, StreamConfig, SupportedBufferSize};
-use model::{Model, Observation};
-use num::{FromPrimitive, ToPrimitive};
+use decoder::Decoder;
+use emission::{build_model, EmissionKind};
+use key::{
+    Key, KeyChange, KeyEstimator, KeyInference, KeyRegion, KeyTransitions, ModalKey, ScaleEstimate,
+};
+use lock::{Locks, MidiControl, Trigger};
+use mapping::{Bounds, Harmony, Interval, Mapping, Scales, Snap, SoloMode};
+use midi::{parse_ump, MidiMessage};
+use model::{chord_to_num, num_to_chord, ModelParams, Observation, VChords};
+use preset::Preset;
+use progression::parse_chord_symbol;
+use routing::{ControllerRule, MessageKind, Routing};
+use semi_markov::{decode_segments, segment_posteriors, Durations};
 use serde::{Deserialize, Serialize};
-use strum::EnumCount;
+use voice::{Output, Voice, Voices};
+use voicing::{PadChord, Voicer, VoicingStyle};
 use websocket::Message;
 
-use std::collections::{HashSet, VecDeque};
+use std::collections::VecDeque;
 use std::f32::consts::PI;
 use std::io::{stdin, BufRead};
+use std::path::PathBuf;
 use std::process::Command;
+use std::sync::mpsc::RecvTimeoutError;
 use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::{default::default, thread};
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use itertools::Itertools;
 
-use crate::model::NUM_CHORDS;
-
 #[derive(Serialize, Debug)]
 struct InferenceEvent {
     chord: Chord,
     chord_inferences: Vec<ChordInference>,
-    scale: Scale,
+    scale: ScaleEstimate,
+    key: Key,
+    key_confidence: f32,
+    /// Posterior probability of `chord` at the latest observation.
+    confidence: f32,
+    alternatives: Vec<ChordProbability>,
+}
+#[derive(Serialize, Debug)]
+struct ChordProbability {
+    chord: Chord,
+    probability: f32,
 }
 #[derive(Deserialize)]
 enum WebInEvent {
     SoloMode(SoloMode),
-}
-#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
-enum SoloMode {
-    Chord,
-    Nearest,
-    Transpose,
+    Capture(Option<String>),
+    ResetAdaptation,
+    /// Switches the transition prior to a style preset, or back to the model's with `None`.
+    Preset(Option<Preset>),
+    /// Locks the scale to the named key, e.g. "D", "Am" or "D dorian", or releases it with `None`.
+    LockScale(Option<String>),
+    /// Holds the current chord until released.
+    FreezeChord(bool),
+    /// Forces the named chord, e.g. "Am", until released with `None`.
+    ForceChord(Option<String>),
+    /// Binds the next MIDI note or controller to a trigger, or cancels learning with `None`.
+    LearnTrigger(Option<Trigger>),
+    /// Silences every note and resets controllers on the destination.
+    Panic,
+    /// Solos over the named scale on the key's tonic, or the key's own mode with `None`.
+    SoloScale(Option<String>),
+    ArpPattern(Pattern),
 }
 #[derive(Serialize, Debug)]
 #[serde(tag = "type")]
 enum WebOutEvent {
     InferenceEvent(InferenceEvent),
     MidiEvent(MidiEvent),
+    FollowEvent(FollowEvent),
+    LockState(Locks),
+    KeyChange(KeyChangeEvent),
     Beat,
+    /// A capture label that is not a major or minor chord; nothing is being captured.
+    CaptureError {
+        message: String,
+    },
+    /// A scale or chord to lock to that does not parse; the locks are unchanged.
+    LockError {
+        message: String,
+    },
+    /// The scales solo modes can use, sent to each client that connects.
+    SoloScales {
+        names: Vec<String>,
+    },
+}
+/// A settled key change, with the key regions of the session so far. Times are in seconds since
+/// the audio stream started.
+#[derive(Serialize, Debug)]
+struct KeyChangeEvent {
+    #[serde(flatten)]
+    change: KeyChange,
+    seconds: f32,
+    regions: Vec<TimedKeyRegion>,
+}
+#[derive(Serialize, Debug)]
+struct TimedKeyRegion {
+    #[serde(flatten)]
+    region: KeyRegion,
+    start_seconds: f32,
+    end_seconds: f32,
+}
+/// Position in the chart given with --chart, counting bars and beats from 0.
+#[derive(Serialize, Debug)]
+struct FollowEvent {
+    bar: usize,
+    beat: usize,
+    confidence: f32,
+    chord: Chord,
+    upcoming: Chord,
+    next_chord: Chord,
+    beats_to_next: usize,
 }
 #[derive(Serialize, Debug)]
 struct MidiEvent {
@@ -59,6 +161,7 @@ struct MidiEvent {
 struct ChordInference {
     y: Vec<f32>,
     chord: Chord,
+    confidence: f32,
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -84,16 +187,213 @@ struct Args {
     chrome: bool,
     #[arg(long, default_value_t = false)]
     disable_output: bool,
+    /// Send everything on this MIDI channel, 1-16, instead of the controller's
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=16))]
+    output_channel: Option<u8>,
+    /// Play every note at this velocity instead of the played one
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=127))]
+    fixed_velocity: Option<u8>,
+    /// Renumber a controller on its way through, e.g. 1=74, or drop it with 1=
+    #[arg(long)]
+    map_cc: Vec<ControllerRule>,
+    /// Keep these messages from the controller from reaching the destination
+    #[arg(long, value_enum)]
+    block: Vec<MessageKind>,
+    /// Scale to solo over on the detected tonic instead of its mode, e.g. blues
+    #[arg(long)]
+    solo_scale: Option<String>,
+    /// JSON file of extra scales for --solo-scale, e.g. {"hirajoshi": [0, 2, 3, 7, 8]}
+    #[arg(long)]
+    scales: Option<PathBuf>,
+    /// Which way notes outside the scale snap
+    #[arg(long, value_enum, default_value_t = Snap::Nearest)]
+    snap: Snap,
+    /// How to bring mapped notes beyond the MIDI range back into it
+    #[arg(long, value_enum, default_value_t = Bounds::Fold)]
+    note_bounds: Bounds,
+    /// Order of the notes in arpeggio mode
+    #[arg(long, value_enum, default_value_t = Pattern::Up)]
+    arp_pattern: Pattern,
+    /// Arpeggio notes per beat
+    #[arg(long, default_value_t = 4)]
+    arp_rate: u32,
+    /// Fraction of each arpeggio step the note sounds for
+    #[arg(long, default_value_t = 0.5)]
+    arp_gate: f32,
+    /// Interval of harmony voices, e.g. 3 for a third above, -6 for a sixth below, or chord
+    #[arg(long, default_value = "-3", allow_hyphen_values = true)]
+    harmony: Interval,
+    /// How many harmony voices to add to each note, 1-4
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=4))]
+    harmony_voices: u8,
+    /// Send harmony voices on this MIDI channel, 1-16, instead of the played note's
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=16))]
+    harmony_channel: Option<u8>,
+    /// How the chord pad voices chords
+    #[arg(long, value_enum, default_value_t = VoicingStyle::Close)]
+    voicing: VoicingStyle,
+    /// Which chord each key plays on the chord pad
+    #[arg(long, value_enum, default_value_t = PadChord::Detected)]
+    pad_chord: PadChord,
+    /// Play a bass line in this style from the detected chords
+    #[arg(long, value_enum)]
+    bass: Option<BassStyle>,
+    /// MIDI channel, 1-16, for the bass line
+    #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u8).range(1..=16))]
+    bass_channel: u8,
+    /// Send the bass line here instead of to the destination
+    #[arg(long)]
+    bass_destination: Option<String>,
+    /// Hold the detected chord on this MIDI channel, 1-16, for recording, with its root on
+    /// controller 20 (C to B as 0 to 127 in steps of 11 or 12) and its quality on controller 21 (0
+    /// major, 127 minor)
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=16))]
+    chord_track: Option<u8>,
+    /// Send the chord track here instead of to the destination
+    #[arg(long)]
+    chord_track_destination: Option<String>,
+    /// Voice-lead the chord track in this style instead of holding root position triads
+    #[arg(long, value_enum)]
+    chord_track_voicing: Option<VoicingStyle>,
+    #[arg(long)]
+    model: Option<PathBuf>,
+    #[arg(long)]
+    capture: Option<String>,
+    #[arg(long, default_value = "samples")]
+    samples: PathBuf,
+    #[arg(long, default_value_t = false)]
+    adapt: bool,
+    #[arg(long, default_value_t = 0.995)]
+    forgetting: f32,
+    #[arg(long, default_value_t = 20.0)]
+    adaptation_strength: f32,
+    #[arg(long, default_value_t = 0.005)]
+    key_change: f32,
+    #[arg(long, default_value_t = 4)]
+    lag: usize,
+    #[arg(long)]
+    record: Option<PathBuf>,
+    #[arg(long, default_value_t = 0.0)]
+    min_confidence: f32,
+    #[arg(long, value_enum, default_value_t = EmissionKind::Gaussian)]
+    emission: EmissionKind,
+    #[arg(long)]
+    mlp_weights: Option<PathBuf>,
+    /// Decode chords with explicit beat durations instead of geometric ones
+    #[arg(long, default_value_t = false)]
+    semi_markov: bool,
+    #[arg(
+        long,
+        default_value_t = 4,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    beats_per_bar: usize,
+    /// Style preset replacing the model's chord transitions
+    #[arg(long, value_enum)]
+    preset: Option<Preset>,
+    /// Observations (beats) of chroma the key estimator remembers
+    #[arg(long, default_value_t = 16.0)]
+    key_horizon: f32,
+    /// How much better another key must correlate before the estimate moves
+    #[arg(long, default_value_t = 0.05)]
+    key_hysteresis: f32,
+    /// Follow this chord chart instead of recognizing chords freely
+    #[arg(long)]
+    chart: Option<PathBuf>,
+    #[command(subcommand)]
+    action: Option<Action>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Action {
+    /// Fit the chord emission models to captured samples and write a model file
+    Train {
+        #[arg(long)]
+        base: Option<PathBuf>,
+        #[arg(long, default_value = "model.json")]
+        output: PathBuf,
+        /// Gaussian mixture components per chord quality
+        #[arg(long, default_value_t = 1)]
+        components: usize,
+    },
+    /// Estimate chord transition probabilities from .lab, ChordPro or plain text progressions
+    Learn {
+        corpus: Vec<PathBuf>,
+        #[arg(long)]
+        base: Option<PathBuf>,
+        #[arg(long, default_value = "model.json")]
+        output: PathBuf,
+        #[arg(long, default_value_t = 0.1)]
+        smoothing: f32,
+        #[arg(long, default_value_t = 0.2)]
+        self_transition: f32,
+        #[arg(long, default_value_t = false)]
+        no_transpose: bool,
+    },
+    /// Print the chord timeline and key regions of a recording made with --record
+    Analyze { recording: PathBuf },
+    /// Inspect a model file
+    Model {
+        #[command(subcommand)]
+        command: ModelCommand,
+    },
+    /// Compare emission scorers on held out captured samples
+    Evaluate {
+        #[arg(long)]
+        base: Option<PathBuf>,
+        #[arg(long, default_value_t = 3)]
+        components: usize,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum ModelCommand {
+    /// Print the vocabulary, transitions, covariance spectra and mean chroma of a model, by
+    /// default the one given with --model
+    Inspect { path: Option<PathBuf> },
 }
 
 #[derive(Debug)]
 enum Event {
-    Note(bool, u8),
-    Chords(Chords),
-    Scale(Scale),
+    Midi(MidiMessage),
+    Lock(LockEvent),
+    Panic,
+    /// Releases whatever still sounds and stops remapping.
+    Shutdown,
+    /// Decoded chords, and the posterior probability of the latest one.
+    Chords(Chords, f32),
+    Scale(ModalKey),
+    SoloScale(Option<String>),
     SoloMode(SoloMode),
+    ArpPattern(Pattern),
+    /// An onset heard by the beat detector.
+    Beat,
+    /// The chart's next chord, how many beats away it is, and the beat of the bar being heard.
+    Upcoming(Chord, usize, usize),
 }
 type Chords = Vec<Chord>;
+/// How many recent observations are shown and handed to the remapper.
+const WINDOW: usize = 24;
+/// How many runner-up chords are reported with each inference.
+const ALTERNATIVES: usize = 3;
+/// How many key candidates are reported with each inference.
+const SCALE_CANDIDATES: usize = 3;
+/// Manual overrides for the remapper.
+#[derive(Debug)]
+enum LockEvent {
+    Scale(Option<ModalKey>),
+    Freeze(bool),
+    Force(Option<Chord>),
+    Learn(Option<Trigger>),
+    /// Resends the lock state, e.g. to a newly connected client.
+    Announce,
+}
+/// Commands for the model, which lives on the audio thread.
+#[derive(Debug)]
+enum ModelEvent {
+    ResetAdaptation,
+    Preset(Option<Preset>),
+}
 
 fn main() {
     let args = Args::parse();
@@ -103,21 +403,125 @@ fn main() {
         destination,
         audio,
         chrome,
+        model,
+        capture,
+        samples,
+        adapt,
+        forgetting,
+        adaptation_strength,
+        key_change,
+        lag,
+        record,
+        emission,
+        mlp_weights,
+        semi_markov,
+        beats_per_bar,
+        preset,
+        chart,
+        key_horizon,
+        key_hysteresis,
+        action,
         ..
     } = args.clone();
+    match action {
+        Some(Action::Train {
+            base,
+            output,
+            components,
+        }) => {
+            let base = base
+                .map(|p| ModelParams::load(&p).unwrap_or_else(|e| panic!("{e}")))
+                .unwrap_or_default();
+            train::train(&samples, base, components, &output);
+            return;
+        }
+        Some(Action::Learn {
+            corpus,
+            base,
+            output,
+            smoothing,
+            self_transition,
+            no_transpose,
+        }) => {
+            let mut params = base
+                .map(|p| ModelParams::load(&p).unwrap_or_else(|e| panic!("{e}")))
+                .unwrap_or_default();
+            let sequences = corpus
+                .iter()
+                .map(|p| progression::read_progression(p))
+                .collect_vec();
+            params.transitions = Some(progression::learn_transitions(
+                &sequences,
+                smoothing,
+                self_transition,
+                !no_transpose,
+            ));
+            params.save(&output);
+            return;
+        }
+        Some(Action::Analyze { recording }) => {
+            let params = model
+                .map(|p| ModelParams::load(&p).unwrap_or_else(|e| panic!("{e}")))
+                .unwrap_or_default();
+            let mut model = build_model(&params, emission, mlp_weights.as_deref())
+                .unwrap_or_else(|e| panic!("{e}"));
+            if let Some(preset) = preset {
+                model.set_transitions(Some(&preset.transitions()));
+            }
+            let durations = semi_markov.then(|| Durations::new(beats_per_bar));
+            analysis::analyze(
+                &recording,
+                &model,
+                key_change,
+                durations.as_ref(),
+                KeyEstimator::new(key_horizon, key_hysteresis),
+            );
+            return;
+        }
+        Some(Action::Model {
+            command: ModelCommand::Inspect { path },
+        }) => {
+            let params = path
+                .or(model)
+                .map(|p| ModelParams::load(&p).unwrap_or_else(|e| panic!("{e}")))
+                .unwrap_or_default();
+            inspect::inspect(&params);
+            return;
+        }
+        Some(Action::Evaluate { base, components }) => {
+            let base = base
+                .map(|p| ModelParams::load(&p).unwrap_or_else(|e| panic!("{e}")))
+                .unwrap_or_default();
+            evaluate::evaluate(&samples, base, components, mlp_weights.as_deref());
+            return;
+        }
+        None => {}
+    }
+    let capture_mutex = Arc::new(Mutex::new(capture.as_deref().map(|label| {
+        capture::parse_chord(label).unwrap_or_else(|| panic!("Cannot capture '{label}'"))
+    })));
+    let capture_mutex_web = capture_mutex.clone();
+    let t_capture = capture::spawn_writer(samples);
+    let t_capture_web = t_capture.clone();
+    let t_record = record.map(capture::spawn_recorder);
     let (tx, rx) = mpsc::channel();
     let tx2 = tx.clone();
+    let tx_shutdown = tx.clone();
     let (t_web, r_web) = mpsc::channel::<WebOutEvent>();
     let (t_audio, r_audio) = mpsc::channel::<Vec<f32>>();
+    let (t_model, r_model) = mpsc::channel::<ModelEvent>();
     let beat_mutex = Arc::new(Mutex::new(false));
     let beat_mutex_beat = beat_mutex.clone();
     let t_web_beat = t_web.clone();
+    let t_web_errors = t_web.clone();
+    let tx_beat = tx.clone();
     thread::spawn(move || {
         let mut beat = Onset::new(aubio::OnsetMode::SpecFlux, 1024, 512, 44100).unwrap();
         for data in r_audio {
             if beat.do_result(&data).unwrap() > 0.0 {
                 *beat_mutex_beat.lock().unwrap() = true;
                 t_web_beat.send(WebOutEvent::Beat).unwrap();
+                tx_beat.send(Event::Beat).unwrap();
             }
         }
     });
@@ -136,12 +540,30 @@ fn main() {
     });
     let tx_web = tx.clone();
     thread::spawn(move || {
-        use websocket::sync::Server;
+        use std::net::TcpStream;
+        use websocket::sync::{Server, Writer};
         let server = Server::bind("127.0.0.1:1234").unwrap();
+        let clients = Arc::new(Mutex::new(Vec::<Writer<TcpStream>>::new()));
+        let clients_broadcast = clients.clone();
+        thread::spawn(move || {
+            for event in r_web.iter() {
+                let message = Message::text(serde_json::to_string(&event).unwrap());
+                clients_broadcast
+                    .lock()
+                    .unwrap()
+                    .retain_mut(|sender| sender.send_message(&message).is_ok());
+            }
+        });
         for client in server.filter_map(Result::ok) {
-            let (mut receiver, mut sender) = client.accept().unwrap().split().unwrap();
+            let (mut receiver, sender) = client.accept().unwrap().split().unwrap();
+            clients.lock().unwrap().push(sender);
             let tx_web = tx_web.clone();
+            let capture_mutex_web = capture_mutex_web.clone();
+            let t_capture_web = t_capture_web.clone();
+            let t_model = t_model.clone();
+            let t_web_errors = t_web_errors.clone();
             tx_web.send(Event::SoloMode(SoloMode::Chord)).unwrap();
+            tx_web.send(Event::Lock(LockEvent::Announce)).unwrap();
             thread::spawn(move || {
                 for event in receiver.incoming_messages().flatten() {
                     if let websocket::OwnedMessage::Text(t) = event {
@@ -149,18 +571,73 @@ fn main() {
                             WebInEvent::SoloMode(solo_mode) => {
                                 tx_web.send(Event::SoloMode(solo_mode)).unwrap();
                             }
+                            WebInEvent::Capture(label) => {
+                                let chord = label.as_deref().and_then(capture::parse_chord);
+                                if let (Some(label), None) = (&label, &chord) {
+                                    t_web_errors
+                                        .send(WebOutEvent::CaptureError {
+                                            message: format!(
+                                                "Cannot capture '{label}': use a major or minor chord"
+                                            ),
+                                        })
+                                        .unwrap();
+                                }
+                                let stopped = chord.is_none();
+                                *capture_mutex_web.lock().unwrap() = chord;
+                                if stopped {
+                                    t_capture_web.send(None).unwrap();
+                                }
+                            }
+                            WebInEvent::ResetAdaptation => {
+                                t_model.send(ModelEvent::ResetAdaptation).unwrap();
+                            }
+                            WebInEvent::Preset(preset) => {
+                                t_model.send(ModelEvent::Preset(preset)).unwrap();
+                            }
+                            WebInEvent::LockScale(label) => {
+                                match label.map(|label| label.parse()).transpose() {
+                                    Ok(scale) => {
+                                        tx_web.send(Event::Lock(LockEvent::Scale(scale))).unwrap()
+                                    }
+                                    Err(message) => t_web_errors
+                                        .send(WebOutEvent::LockError { message })
+                                        .unwrap(),
+                                }
+                            }
+                            WebInEvent::FreezeChord(freeze) => {
+                                tx_web.send(Event::Lock(LockEvent::Freeze(freeze))).unwrap();
+                            }
+                            WebInEvent::ForceChord(label) => {
+                                let chord = label
+                                    .map(|label| {
+                                        parse_chord_symbol(&label)
+                                            .map(num_to_chord)
+                                            .ok_or_else(|| format!("Invalid chord '{label}'"))
+                                    })
+                                    .transpose();
+                                match chord {
+                                    Ok(chord) => {
+                                        tx_web.send(Event::Lock(LockEvent::Force(chord))).unwrap()
+                                    }
+                                    Err(message) => t_web_errors
+                                        .send(WebOutEvent::LockError { message })
+                                        .unwrap(),
+                                }
+                            }
+                            WebInEvent::LearnTrigger(trigger) => {
+                                tx_web.send(Event::Lock(LockEvent::Learn(trigger))).unwrap();
+                            }
+                            WebInEvent::Panic => tx_web.send(Event::Panic).unwrap(),
+                            WebInEvent::SoloScale(name) => {
+                                tx_web.send(Event::SoloScale(name)).unwrap();
+                            }
+                            WebInEvent::ArpPattern(pattern) => {
+                                tx_web.send(Event::ArpPattern(pattern)).unwrap();
+                            }
                         }
                     }
                 }
             });
-            for chord in r_web.iter() {
-                if sender
-                    .send_message(&Message::text(&serde_json::to_string(&chord).unwrap()))
-                    .is_err()
-                {
-                    break;
-                }
-            }
         }
     });
     let t_web_audio = t_web.clone();
@@ -183,14 +660,47 @@ fn main() {
                 };
             }
             let mut buffer: VecDeque<f32> = VecDeque::new();
-            let model = Model::default();
+            let params = model
+                .map(|p| ModelParams::load(&p).unwrap_or_else(|e| panic!("{e}")))
+                .unwrap_or_default();
+            let mut model = build_model(&params, emission, mlp_weights.as_deref())
+                .unwrap_or_else(|e| panic!("{e}"));
+            if let Some(preset) = preset {
+                model.set_transitions(Some(&preset.transitions()));
+            }
+            if adapt {
+                model.enable_adaptation(forgetting, adaptation_strength);
+            }
             let mut observations: VecDeque<Observation> = [default()].into();
+            let mut decoder = Decoder::new(key_change, lag);
+            // Smoothed posteriors of the completed observations, updated on beats.
+            let mut smoothed: VecDeque<VChords> = VecDeque::new();
+            let mut durations =
+                semi_markov.then(|| (Durations::new(beats_per_bar), KeyTransitions::new(&model)));
+            let mut key_estimator = KeyEstimator::new(key_horizon, key_hysteresis);
+            // When each beat pushed to the key estimator started.
+            let stream_start = Instant::now();
+            let mut beat_times: Vec<f32> = vec![];
             let max_size = config.sample_rate.0 as usize * milliseconds as usize / 1000;
             let mut current_agg_count = 0.0;
+            let mut follower = chart.map(|path| Follower::new(read_chart(&path, beats_per_bar)));
+            // Audio callbacks in the last complete beat.
+            let mut beat_length = 0.0;
             let stream = device
                 .build_input_stream(
                     &config,
                     move |data: &[f32], _| {
+                        for event in r_model.try_iter() {
+                            match event {
+                                ModelEvent::ResetAdaptation => model.reset_adaptation(),
+                                ModelEvent::Preset(preset) => match preset {
+                                    Some(preset) => {
+                                        model.set_transitions(Some(&preset.transitions()))
+                                    }
+                                    None => model.set_transitions(params.transitions.as_ref()),
+                                },
+                            }
+                        }
                         let data = data
                             .chunks_exact(config.channels as usize)
                             .map(|c| c.iter().sum::<f32>())
@@ -198,6 +708,8 @@ fn main() {
                         if data.iter().map(|f| f.abs()).sum::<f32>() < 1e-1 {
                             observations.clear();
                             observations.push_back(default());
+                            decoder.reset();
+                            smoothed.clear();
                             current_agg_count = 0.0;
                             buffer.clear();
                         }
@@ -236,7 +748,54 @@ fn main() {
                         }
                         new_feature.normalize_mut();
                         if beat && (observations.len() > 1 || current_agg_count > 3.0) {
+                            if let Some(chord) = capture_mutex.lock().unwrap().clone() {
+                                if current_agg_count >= 3.0 {
+                                    t_capture
+                                        .send(Some((chord, *observations.back().unwrap())))
+                                        .unwrap();
+                                }
+                            }
+                            model.adapt(observations.make_contiguous());
+                            decoder.push(&model, observations.make_contiguous());
+                            beat_times.push(stream_start.elapsed().as_secs_f32());
+                            if let Some(change) = key_estimator.push(observations.back().unwrap()) {
+                                // Regions end after the latest beat, which ends now.
+                                let seconds = |beat: usize| {
+                                    beat_times
+                                        .get(beat)
+                                        .copied()
+                                        .unwrap_or_else(|| stream_start.elapsed().as_secs_f32())
+                                };
+                                let regions = key_estimator
+                                    .regions()
+                                    .iter()
+                                    .map(|&region| TimedKeyRegion {
+                                        region,
+                                        start_seconds: seconds(region.start),
+                                        end_seconds: seconds(region.end),
+                                    })
+                                    .collect();
+                                t_web_audio
+                                    .send(WebOutEvent::KeyChange(KeyChangeEvent {
+                                        seconds: seconds(change.beat),
+                                        change,
+                                        regions,
+                                    }))
+                                    .unwrap();
+                            }
+                            if let Some(follower) = &mut follower {
+                                follower.push(&model, observations.make_contiguous());
+                            }
+                            if let Some(t_record) = &t_record {
+                                t_record.send(*observations.back().unwrap()).unwrap();
+                            }
+                            if durations.is_none() {
+                                smoothed = decoder
+                                    .posteriors(&model, observations.make_contiguous())
+                                    .into();
+                            }
                             observations.push_back(new_feature);
+                            beat_length = current_agg_count;
                             current_agg_count = 1.0;
                         } else {
                             let features = observations.back_mut().unwrap();
@@ -245,27 +804,123 @@ fn main() {
                             (*features).normalize_mut();
                             current_agg_count += 1.0;
                         }
-                        if observations.len() > NUM_CHORDS {
+                        if observations.len() > WINDOW {
                             observations.pop_front();
+                            smoothed.pop_front();
                         }
                         if current_agg_count < 3.0 {
                             return;
                         }
-                        let chords = model.infer_viterbi(observations.make_contiguous());
+                        let window = observations.len();
+                        let KeyInference {
+                            chords,
+                            keys,
+                            key,
+                            key_confidence,
+                            chord_posterior,
+                        } = decoder.peek(&model, observations.make_contiguous(), window);
+                        // Chords, and their posteriors, from the duration decoder when enabled.
+                        let (chords, posteriors) = match &mut durations {
+                            Some((durations, transitions)) => {
+                                transitions.update(&model);
+                                let observations = observations.make_contiguous();
+                                let chords = decode_segments(
+                                    &model,
+                                    observations,
+                                    &keys,
+                                    durations,
+                                    transitions,
+                                );
+                                let posteriors = segment_posteriors(
+                                    &model,
+                                    observations,
+                                    &keys,
+                                    durations,
+                                    transitions,
+                                );
+                                (
+                                    chords.into_iter().map(num_to_chord).collect_vec(),
+                                    posteriors,
+                                )
+                            }
+                            // The last observation is still being heard: its filtered posterior.
+                            None => (
+                                chords,
+                                smoothed
+                                    .iter()
+                                    .copied()
+                                    .chain([chord_posterior])
+                                    .collect_vec(),
+                            ),
+                        };
+                        let chord_posterior = match &durations {
+                            Some(_) => posteriors[window - 1],
+                            None => chord_posterior,
+                        };
                         let chord = &chords[chords.len() - 1];
-                        tx.send(Event::Chords(chords.clone())).unwrap();
-                        let scale = scale_from_chords(&chords);
-                        tx.send(Event::Scale(scale)).unwrap();
+                        let confidence = chord_posterior[chord_to_num(chord)];
+                        match follower
+                            .as_ref()
+                            .map(|f| f.peek(&model, observations.make_contiguous()))
+                        {
+                            Some(position) => {
+                                // Anticipate a chord change in the second half of the beat before.
+                                let anticipate = position.beats_to_next == 1
+                                    && current_agg_count >= beat_length / 2.0;
+                                let (chord, confidence) = if anticipate {
+                                    (&position.next_chord, position.next_confidence)
+                                } else {
+                                    (&position.chord, position.chord_confidence)
+                                };
+                                tx.send(Event::Chords(vec![chord.clone()], confidence))
+                                    .unwrap();
+                                tx.send(Event::Upcoming(
+                                    position.next_chord.clone(),
+                                    position.beats_to_next,
+                                    position.beat_in_bar,
+                                ))
+                                .unwrap();
+                                t_web_audio
+                                    .send(WebOutEvent::FollowEvent(FollowEvent {
+                                        bar: position.bar,
+                                        beat: position.beat_in_bar,
+                                        confidence: position.confidence,
+                                        chord: position.chord,
+                                        upcoming: position.upcoming,
+                                        next_chord: position.next_chord,
+                                        beats_to_next: position.beats_to_next,
+                                    }))
+                                    .unwrap();
+                            }
+                            None => tx.send(Event::Chords(chords.clone(), confidence)).unwrap(),
+                        }
+                        let scale = key_estimator.estimate(SCALE_CANDIDATES);
+                        tx.send(Event::Scale(scale.key)).unwrap();
                         t_web_audio
                             .send(WebOutEvent::InferenceEvent(InferenceEvent {
                                 scale,
+                                key,
+                                key_confidence,
                                 chord: chord.clone(),
+                                confidence,
+                                alternatives: (0..chord_posterior.len())
+                                    .filter(|&c| c != chord_to_num(chord))
+                                    .sorted_by(|&a, &b| {
+                                        chord_posterior[b].total_cmp(&chord_posterior[a])
+                                    })
+                                    .take(ALTERNATIVES)
+                                    .map(|c| ChordProbability {
+                                        chord: num_to_chord(c),
+                                        probability: chord_posterior[c],
+                                    })
+                                    .collect(),
                                 chord_inferences: observations
                                     .iter()
                                     .enumerate()
                                     .map(|(i, x)| ChordInference {
                                         chord: chords[i].clone(),
                                         y: x.iter().copied().collect(),
+                                        confidence: posteriors[i][chord_to_num(&chords[i])],
                                     })
                                     .collect(),
                             }))
@@ -283,39 +938,229 @@ fn main() {
     thread::spawn(move || {
         let _hack = publish_midi_in_events(source.unwrap_or_else(|| "OP-1".into()), tx2);
         block();
+        tx_shutdown.send(Event::Shutdown).unwrap();
+    });
+    let routing = Routing {
+        channel: args.output_channel.map(|c| c - 1),
+        velocity: args.fixed_velocity,
+        controllers: args.map_cc.clone(),
+        blocked: args.block.clone(),
+    };
+    let mut mapping = Mapping {
+        scales: Scales::load(args.scales.as_deref()),
+        solo_scale: None,
+        snap: args.snap,
+        bounds: args.note_bounds,
+        harmony: Harmony {
+            interval: args.harmony,
+            voices: args.harmony_voices.into(),
+            channel: args.harmony_channel.map(|c| c - 1),
+        },
+        pad_chord: args.pad_chord,
+        voicer: Voicer::new(args.voicing),
+    };
+    mapping
+        .set_solo_scale(args.solo_scale.clone())
+        .unwrap_or_else(|e| panic!("{e}"));
+    let destination = destination.unwrap_or_else(|| "Garage".into());
+    let bass = args.bass.map(|style| {
+        let name = args.bass_destination.as_deref().unwrap_or(&destination);
+        let player = Player::new(name, args.disable_output, Routing::default());
+        Bass::new(player, style, args.bass_channel - 1, beats_per_bar)
     });
-    output_remapped_midi_notes(destination, rx, args.disable_output, t_web);
+    let chord_track = args.chord_track.map(|channel| {
+        let name = args
+            .chord_track_destination
+            .as_deref()
+            .unwrap_or(&destination);
+        let player = Player::new(name, args.disable_output, Routing::default());
+        ChordTrack::new(player, channel - 1, args.chord_track_voicing)
+    });
+    let player = Player::new(&destination, args.disable_output, routing);
+    let arpeggiator = Arpeggiator::new(args.arp_pattern, args.arp_rate, args.arp_gate);
+    output_remapped_midi_notes(
+        player,
+        rx,
+        mapping,
+        arpeggiator,
+        Accompaniment { bass, chord_track },
+        args.min_confidence,
+        t_web,
+    );
+}
+
+/// Outputs that play along with the detected chords rather than the controller.
+struct Accompaniment {
+    bass: Option<Bass<Player>>,
+    chord_track: Option<ChordTrack<Player>>,
 }
 
 fn output_remapped_midi_notes(
-    destination: Option<String>,
+    player: Player,
     rx: mpsc::Receiver<Event>,
-    disable_output: bool,
+    mut mapping: Mapping,
+    mut arpeggiator: Arpeggiator,
+    accompaniment: Accompaniment,
+    min_confidence: f32,
     t_web: mpsc::Sender<WebOutEvent>,
 ) {
-    let player = Player::new(
-        &destination.unwrap_or_else(|| "Garage".into()),
-        disable_output,
-    );
-    let mut active_chord = "C".parse().unwrap();
-    let mut scale: Scale = "C".parse().unwrap();
-    let mut midi_note_remapping_history = vec![0; 256];
+    let Accompaniment {
+        mut bass,
+        mut chord_track,
+    } = accompaniment;
+    let mut voices = Voices::new(player);
+    let mut active_chord: Chord = "C".parse().unwrap();
+    let mut scale: ModalKey = "C".parse().unwrap();
     let mut solo_mode = SoloMode::Chord;
-    for event in rx {
+    let mut locks = Locks::default();
+    loop {
+        // Arpeggio steps and bass offbeats fall due between events, so wait no longer than the
+        // next one.
+        arpeggiator.tick(Instant::now(), locks.chord(&active_chord), &mut voices);
+        if let Some(bass) = &mut bass {
+            bass.tick(Instant::now());
+        }
+        let timeout = [
+            arpeggiator.deadline(),
+            bass.as_ref().and_then(Bass::deadline),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+        .map_or(Duration::MAX, |d| {
+            d.saturating_duration_since(Instant::now())
+        });
+        let event = match rx.recv_timeout(timeout) {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
         match event {
             Event::SoloMode(mode) => {
+                // Held notes were mapped the old way.
+                if mode != solo_mode {
+                    arpeggiator.stop(&mut voices);
+                    voices.all_notes_off();
+                }
                 solo_mode = mode;
             }
-            Event::Chords(chords) => {
-                active_chord = chords[chords.len() - 1].clone();
+            Event::ArpPattern(pattern) => arpeggiator.pattern = pattern,
+            Event::Beat => {
+                arpeggiator.beat(Instant::now());
+                if let Some(bass) = &mut bass {
+                    bass.beat(Instant::now(), locks.chord(&active_chord));
+                }
+            }
+            Event::Upcoming(chord, beats, beat_in_bar) => {
+                if let Some(bass) = &mut bass {
+                    bass.upcoming(chord, beats, beat_in_bar);
+                }
+            }
+            Event::Panic => {
+                arpeggiator.stop(&mut voices);
+                if let Some(bass) = &mut bass {
+                    bass.stop();
+                }
+                if let Some(track) = &mut chord_track {
+                    track.stop();
+                }
+                voices.panic();
+            }
+            Event::SoloScale(name) => {
+                if let Err(e) = mapping.set_solo_scale(name) {
+                    eprintln!("{e}");
+                }
+            }
+            Event::Shutdown => break,
+            Event::Lock(lock) => {
+                match lock {
+                    LockEvent::Scale(locked) => locks.lock_scale(locked),
+                    LockEvent::Freeze(freeze) => {
+                        locks.lock_chord(freeze.then(|| active_chord.clone()))
+                    }
+                    LockEvent::Force(chord) => locks.lock_chord(chord),
+                    LockEvent::Learn(trigger) => locks.learn(trigger),
+                    LockEvent::Announce => t_web
+                        .send(WebOutEvent::SoloScales {
+                            names: mapping.scales.names(),
+                        })
+                        .unwrap(),
+                }
+                if let Some(bass) = &mut bass {
+                    bass.change(Instant::now(), locks.chord(&active_chord));
+                }
+                if let Some(track) = &mut chord_track {
+                    track.update(locks.chord(&active_chord));
+                }
+                t_web.send(WebOutEvent::LockState(locks.clone())).unwrap();
+            }
+            Event::Midi(
+                message @ MidiMessage::ControlChange {
+                    controller, value, ..
+                },
+            ) => {
+                if locks.midi(
+                    MidiControl::Controller(controller),
+                    Some(value >= 64),
+                    &active_chord,
+                    scale,
+                ) {
+                    t_web.send(WebOutEvent::LockState(locks.clone())).unwrap();
+                } else {
+                    voices.output.send(message);
+                }
+            }
+            Event::Chords(chords, confidence) => {
+                // Keep the previous chord rather than switching on a coin flip.
+                if confidence >= min_confidence {
+                    active_chord = chords[chords.len() - 1].clone();
+                }
+                if let Some(bass) = &mut bass {
+                    bass.change(Instant::now(), locks.chord(&active_chord));
+                }
+                if let Some(track) = &mut chord_track {
+                    track.update(locks.chord(&active_chord));
+                }
             }
             Event::Scale(new_scale) => {
                 scale = new_scale;
             }
-            Event::Note(on, note) => {
+            Event::Midi(
+                message @ (MidiMessage::NoteOn {
+                    channel,
+                    note,
+                    velocity,
+                }
+                | MidiMessage::NoteOff {
+                    channel,
+                    note,
+                    velocity,
+                }),
+            ) => {
+                let on = matches!(message, MidiMessage::NoteOn { .. });
+                let control = MidiControl::Note(note);
+                if on && locks.midi(control, None, &active_chord, scale) {
+                    t_web.send(WebOutEvent::LockState(locks.clone())).unwrap();
+                    continue;
+                }
+                if !on && locks.is_bound(control) {
+                    continue;
+                }
+                // Overrides take the place of inference while locked.
+                let active_chord = locks.chord(&active_chord);
+                let scale = locks.scale(scale);
+                if solo_mode == SoloMode::Arpeggio {
+                    if on {
+                        arpeggiator.press(channel, note, velocity, Instant::now());
+                    } else {
+                        arpeggiator.release(channel, note, &mut voices);
+                    }
+                    continue;
+                }
                 if !on {
-                    let mapped_note = midi_note_remapping_history[note as usize];
-                    player.play_off(mapped_note);
+                    let Some(mapped_note) = voices.release(channel, note, velocity) else {
+                        continue;
+                    };
                     t_web
                         .send(WebOutEvent::MidiEvent(MidiEvent {
                             note,
@@ -325,26 +1170,26 @@ fn output_remapped_midi_notes(
                         .unwrap();
                     continue;
                 }
-                let mapped_note = match solo_mode {
-                    SoloMode::Chord => [note, note - 1, note + 1, note - 2, note + 2]
-                        .into_iter()
-                        .find(|note| {
-                            (active_chord.notes()).contains(&(Note::from_u8(*note).unwrap()))
-                        })
-                        .unwrap_or(note),
-                    _ => {
-                        let octave = (note / Note::COUNT as u8) * Note::COUNT as u8;
-                        let index = if solo_mode == SoloMode::Transpose {
-                            note % 12
-                        } else {
-                            [0, 2, 2, 4, 4, 5, 7, 7, 9, 9, 11, 11][(note % 12) as usize]
-                        };
-                        let scale_offset = scale.root.to_u8().unwrap();
-                        octave + index + scale_offset
+                let sounding = if solo_mode == SoloMode::ChordPad {
+                    let pad = mapping.pad(note, active_chord, &scale);
+                    pad.into_iter()
+                        .map(|n| Voice::new(channel, n))
+                        .collect_vec()
+                } else {
+                    let mapped_note = mapping.map(note, solo_mode, active_chord, &scale);
+                    let mut sounding = vec![Voice::new(channel, mapped_note)];
+                    if solo_mode == SoloMode::Harmonize {
+                        sounding.extend(mapping.harmonize(
+                            channel,
+                            mapped_note,
+                            active_chord,
+                            &scale,
+                        ));
                     }
+                    sounding
                 };
-                midi_note_remapping_history[note as usize] = mapped_note;
-                player.play_on(mapped_note);
+                voices.press_voices(channel, note, &sounding, velocity);
+                let mapped_note = sounding[0].note;
                 t_web
                     .send(WebOutEvent::MidiEvent(MidiEvent {
                         note,
@@ -353,6 +1198,31 @@ fn output_remapped_midi_notes(
                     }))
                     .unwrap();
             }
+            Event::Midi(MidiMessage::PolyPressure {
+                channel,
+                note,
+                pressure,
+            }) => {
+                // Aftertouch follows the key to the notes it was mapped to.
+                for voice in voices.sounding(channel, note).to_vec() {
+                    let message = MidiMessage::PolyPressure {
+                        channel: voice.channel,
+                        note: voice.note,
+                        pressure,
+                    };
+                    if voice.own_channel {
+                        voices.output.send_on_own_channel(message);
+                    } else {
+                        voices.output.send(message);
+                    }
+                }
+            }
+            Event::Midi(
+                message @ (MidiMessage::PitchBend { .. }
+                | MidiMessage::ChannelPressure { .. }
+                | MidiMessage::ProgramChange { .. }),
+            ) => voices.output.send(message),
+            Event::Midi(MidiMessage::RealTime(_) | MidiMessage::SystemCommon { .. }) => {}
         }
     }
 }
@@ -365,13 +1235,11 @@ fn publish_midi_in_events(
     let client = Client::new("Example Client").unwrap();
     let mut input_port = client
         .input_port_with_protocol("Example Port", Protocol::Midi10, move |event_list, _| {
-            for event in event_list.iter() {
-                if event.data()[0] == 0x10f80000 {
+            for message in event_list.iter().flat_map(|event| parse_ump(event.data())) {
+                if let MidiMessage::RealTime(_) = message {
                     continue;
                 }
-                let note = ((event.data()[0]) << 15 >> 23) as u8;
-                let on = (event.data()[0] & 1) == 1;
-                tx.send(Event::Note(on, note)).unwrap();
+                tx.send(Event::Midi(message)).unwrap();
             }
         })
         .unwrap();
@@ -431,9 +1299,10 @@ struct Player {
     output_port: OutputPort,
     destination: Destination,
     disable_output: bool,
+    routing: Routing,
 }
 impl Player {
-    fn new(name: &str, disable_output: bool) -> Self {
+    fn new(name: &str, disable_output: bool, routing: Routing) -> Self {
         let client = Client::new("Example Client").unwrap();
         let output_port = client.output_port("Example Port").unwrap();
         let destination = get_destination(name);
@@ -442,55 +1311,35 @@ impl Player {
             output_port,
             destination,
             disable_output,
+            routing,
         }
     }
-    fn play_on(&self, note: u8) {
+    fn play(&mut self, message: MidiMessage, keep_channel: bool) {
         if self.disable_output {
             return;
         }
-        self.output_port
-            .send(
-                &self.destination,
-                &PacketBuffer::new(0, &[0x90, note & 0x7f, 127]),
-            )
-            .unwrap();
-    }
-    fn play_off(&self, note: u8) {
-        if self.disable_output {
+        let Some(message) = self.routing.route(message, keep_channel) else {
             return;
-        }
+        };
         self.output_port
             .send(
                 &self.destination,
-                &PacketBuffer::new(0, &[0x80, note & 0x7f, 127]),
+                &PacketBuffer::new(0, &message.to_bytes()),
             )
             .unwrap();
     }
 }
+impl Output for Player {
+    fn send(&mut self, message: MidiMessage) {
+        self.play(message, false);
+    }
+    fn send_on_own_channel(&mut self, message: MidiMessage) {
+        self.play(message, true);
+    }
+    fn channel_for(&self, channel: u8, own_channel: bool) -> u8 {
+        self.routing.channel_for(channel, own_channel)
+    }
+}
 
 type Features = Observation;
 type F = f32;
-
-fn scale_from_chords(chords: &Chords) -> Scale {
-    let mut candidates = Note::vec()
-        .into_iter()
-        .flat_map(|root| ScaleBuilder::default().root(root).build())
-        .collect_vec();
-    let mut all_notes = HashSet::<Note>::new();
-    for chord in chords.iter().rev() {
-        all_notes.extend(chord.notes());
-        let remaining_candidates = candidates
-            .iter()
-            .copied()
-            .filter(|scale| HashSet::from_iter(scale.notes().into_iter()).is_superset(&all_notes))
-            .collect_vec();
-        match remaining_candidates.len() {
-            0 => return candidates[0],
-            1 => return remaining_candidates[0],
-            _ => {
-                candidates = remaining_candidates;
-            }
-        }
-    }
-    candidates[0]
-}
@@ -0,0 +1,450 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::str::FromStr;
+
+use chords::Chord;
+use clap::ValueEnum;
+use itertools::Itertools;
+use num::ToPrimitive;
+use serde::Deserialize;
+
+use crate::key::{ModalKey, ScaleMode};
+use crate::voice::Voice;
+use crate::voicing::{PadChord, Voicer};
+
+/// The highest MIDI note.
+pub(crate) const MAX_NOTE: i16 = 127;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SoloMode {
+    /// Snap to the nearest note of the current chord, within a whole tone.
+    Chord,
+    /// Snap notes, played as if in C, onto the current scale.
+    Nearest,
+    /// Transpose C to the tonic of the current scale.
+    Transpose,
+    /// Arpeggiate the current chord while keys are held, see `Arpeggiator`.
+    Arpeggio,
+    /// Play notes as they are, with harmony voices from the scale or chord, see `Harmony`.
+    Harmonize,
+    /// Play a voiced chord for each key, see `Voicer`.
+    ChordPad,
+}
+
+/// Which way `SoloMode::Nearest` moves notes outside the scale.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Snap {
+    Down,
+    Up,
+    /// The closer neighbour, or the one above on a tie.
+    Nearest,
+}
+
+/// Scale degrees, as semitones above a tonic, to snap played notes onto.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ScaleMap {
+    tonic: u8,
+    intervals: Vec<u8>,
+}
+impl ScaleMap {
+    /// The scale on `key`'s tonic, with `intervals` instead of those of its mode if given.
+    pub(crate) fn new(key: &ModalKey, intervals: Option<&[u8]>) -> Self {
+        Self {
+            tonic: key.root.to_u8().unwrap(),
+            intervals: intervals.map_or_else(|| mode_intervals(key.mode), <[u8]>::to_vec),
+        }
+    }
+    /// The degree `interval` snaps to, possibly in a neighbouring octave.
+    fn snap(&self, interval: i16, snap: Snap) -> i16 {
+        let degrees = self
+            .intervals
+            .iter()
+            .flat_map(|&i| [i as i16 - 12, i as i16, i as i16 + 12]);
+        match snap {
+            Snap::Down => degrees.filter(|&d| d <= interval).max(),
+            Snap::Up => degrees.filter(|&d| d >= interval).min(),
+            Snap::Nearest => degrees.min_by_key(|&d| ((d - interval).abs(), d < interval)),
+        }
+        .unwrap()
+    }
+    /// Pitch classes of the triad stacked in scale thirds on the degree `note` snaps to, with
+    /// notes taken as if in C like `SoloMode::Nearest`.
+    fn triad(&self, note: u8, snap: Snap) -> Vec<u8> {
+        let interval = self.snap((note % 12) as i16, snap).rem_euclid(12) as u8;
+        let degree = self.intervals.iter().position(|&i| i == interval).unwrap();
+        (0..3)
+            .map(|third| {
+                let i = self.intervals[(degree + 2 * third) % self.intervals.len()];
+                (self.tonic + i) % 12
+            })
+            .collect()
+    }
+    /// Every note of the scale from an octave below the MIDI range to an octave above, ascending.
+    fn notes(&self) -> Vec<i16> {
+        (-1..=11)
+            .flat_map(|octave| {
+                self.intervals
+                    .iter()
+                    .map(move |&i| octave * 12 + (self.tonic + i) as i16 % 12)
+            })
+            .sorted()
+            .collect()
+    }
+}
+
+fn mode_intervals(mode: ScaleMode) -> Vec<u8> {
+    mode.intervals().iter().map(|&i| i as u8).collect()
+}
+
+/// Named scales for solo modes: the key estimator's modes, a few more, and any read from a JSON
+/// file mapping names to semitones above the tonic, e.g. `{"hirajoshi": [0, 2, 3, 7, 8]}`.
+pub(crate) struct Scales(BTreeMap<String, Vec<u8>>);
+impl Scales {
+    pub(crate) fn load(path: Option<&Path>) -> Self {
+        let mut scales: BTreeMap<String, Vec<u8>> = ScaleMode::ALL
+            .iter()
+            .map(|&mode| (mode.name().to_string(), mode_intervals(mode)))
+            .collect();
+        for (name, intervals) in [
+            ("major-pentatonic", &[0, 2, 4, 7, 9][..]),
+            ("minor-pentatonic", &[0, 3, 5, 7, 10]),
+            ("blues", &[0, 3, 5, 6, 7, 10]),
+            ("major-blues", &[0, 2, 3, 4, 7, 9]),
+            ("whole-tone", &[0, 2, 4, 6, 8, 10]),
+        ] {
+            scales.insert(name.into(), intervals.to_vec());
+        }
+        if let Some(path) = path {
+            let contents = std::fs::read_to_string(path)
+                .unwrap_or_else(|e| panic!("Failed to read scales {}: {e}", path.display()));
+            let custom: BTreeMap<String, Vec<u8>> = serde_json::from_str(&contents)
+                .unwrap_or_else(|e| panic!("Failed to parse scales {}: {e}", path.display()));
+            for (name, mut intervals) in custom {
+                assert!(
+                    !intervals.is_empty() && intervals.iter().all(|&i| i < 12),
+                    "Scale {name} needs semitones from 0 to 11"
+                );
+                // Degrees are counted in order.
+                intervals.sort_unstable();
+                intervals.dedup();
+                scales.insert(name, intervals);
+            }
+        }
+        Self(scales)
+    }
+    pub(crate) fn get(&self, name: &str) -> Option<&[u8]> {
+        self.0.get(name).map(Vec::as_slice)
+    }
+    pub(crate) fn names(&self) -> Vec<String> {
+        self.0.keys().cloned().collect()
+    }
+}
+
+/// What to do with a mapped note that falls outside the MIDI note range.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Bounds {
+    /// Move it to the nearest end of the range.
+    Clamp,
+    /// Move it by octaves back into the range, keeping its pitch class.
+    Fold,
+}
+impl Bounds {
+    fn apply(&self, note: i16) -> u8 {
+        let note = match self {
+            Bounds::Clamp => note.clamp(0, MAX_NOTE),
+            Bounds::Fold if note > MAX_NOTE => note - (note - MAX_NOTE + 11) / 12 * 12,
+            Bounds::Fold if note < 0 => note + (11 - note) / 12 * 12,
+            Bounds::Fold => note,
+        };
+        note as u8
+    }
+}
+
+/// The interval between harmony voices: a number of scale degrees, or the next chord tone.
+/// Written like `3` for a diatonic third above, `-6` for a sixth below, `chord` or `-chord`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Interval {
+    /// Scale degrees to move, e.g. -2 for a third below.
+    Diatonic(i16),
+    ChordTone {
+        below: bool,
+    },
+}
+impl FromStr for Interval {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (below, size) = match s.trim().strip_prefix('-') {
+            Some(size) => (true, size),
+            None => (false, s.trim()),
+        };
+        if size == "chord" {
+            return Ok(Interval::ChordTone { below });
+        }
+        match size.parse::<i16>() {
+            Ok(size @ 2..=15) => Ok(Interval::Diatonic(if below { 1 - size } else { size - 1 })),
+            _ => Err(format!(
+                "Expected an interval from 2 to 15 or chord, got '{s}'"
+            )),
+        }
+    }
+}
+
+/// Voices added to each note in `SoloMode::Harmonize`, each an `interval` beyond the last.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Harmony {
+    pub(crate) interval: Interval,
+    pub(crate) voices: usize,
+    /// Channel, from 0, for the added voices, instead of the played note's.
+    pub(crate) channel: Option<u8>,
+}
+
+/// How the remapper maps notes, besides the solo mode.
+pub(crate) struct Mapping {
+    pub(crate) scales: Scales,
+    /// Name of a scale to solo over on the key's tonic, instead of the key's mode.
+    pub(crate) solo_scale: Option<String>,
+    pub(crate) snap: Snap,
+    pub(crate) bounds: Bounds,
+    pub(crate) harmony: Harmony,
+    pub(crate) pad_chord: PadChord,
+    pub(crate) voicer: Voicer,
+}
+impl Mapping {
+    /// Switches the solo scale, or back to the key's mode with `None`. Unknown names are refused.
+    pub(crate) fn set_solo_scale(&mut self, name: Option<String>) -> Result<(), String> {
+        match name {
+            Some(name) if self.scales.get(&name).is_none() => Err(format!("Unknown scale {name}")),
+            name => {
+                self.solo_scale = name;
+                Ok(())
+            }
+        }
+    }
+    fn scale(&self, key: &ModalKey) -> ScaleMap {
+        let intervals = self.solo_scale.as_deref().and_then(|n| self.scales.get(n));
+        ScaleMap::new(key, intervals)
+    }
+    pub(crate) fn map(&self, note: u8, mode: SoloMode, chord: &Chord, key: &ModalKey) -> u8 {
+        map_note(note, mode, chord, &self.scale(key), self.snap, self.bounds)
+    }
+    /// The voiced chord a key plays in `SoloMode::ChordPad`.
+    pub(crate) fn pad(&mut self, note: u8, chord: &Chord, key: &ModalKey) -> Vec<u8> {
+        let tones = match self.pad_chord {
+            PadChord::Detected => chord.notes().iter().map(|n| n.to_u8().unwrap()).collect(),
+            PadChord::Degree => self.scale(key).triad(note, self.snap),
+        };
+        self.voicer.voice(note, &tones)
+    }
+    /// The harmony voices for a note played on `channel`, on their own channel if one is set.
+    pub(crate) fn harmonize(
+        &self,
+        channel: u8,
+        note: u8,
+        chord: &Chord,
+        key: &ModalKey,
+    ) -> Vec<Voice> {
+        harmonize(note, self.harmony, chord, &self.scale(key), self.bounds)
+            .into_iter()
+            .map(|note| Voice {
+                channel: self.harmony.channel.unwrap_or(channel),
+                note,
+                own_channel: self.harmony.channel.is_some(),
+            })
+            .collect()
+    }
+}
+
+/// Harmony voices for `note`, nearest first. Notes off the scale harmonize from the degree below.
+fn harmonize(
+    note: u8,
+    harmony: Harmony,
+    chord: &Chord,
+    scale: &ScaleMap,
+    bounds: Bounds,
+) -> Vec<u8> {
+    let note = note as i16;
+    let voices = 1..=harmony.voices as i16;
+    let harmonized = match harmony.interval {
+        Interval::Diatonic(degrees) => {
+            let notes = scale.notes();
+            let degree = notes.iter().rposition(|&n| n <= note).unwrap() as i16;
+            voices
+                .map(|voice| {
+                    let i = degree + voice * degrees;
+                    // Beyond the list, continue by octaves.
+                    let octaves = i.div_euclid(notes.len() as i16);
+                    notes[i.rem_euclid(notes.len() as i16) as usize] + octaves * 12 * 13
+                })
+                .collect_vec()
+        }
+        Interval::ChordTone { below } => {
+            let tones = chord
+                .notes()
+                .iter()
+                .map(|n| n.to_i16().unwrap())
+                .collect::<Vec<_>>();
+            let step = if below { -1 } else { 1 };
+            let mut n = note;
+            voices
+                .map(|_| {
+                    n += step;
+                    while !tones.contains(&n.rem_euclid(12)) {
+                        n += step;
+                    }
+                    n
+                })
+                .collect_vec()
+        }
+    };
+    harmonized.into_iter().map(|n| bounds.apply(n)).collect()
+}
+
+/// Maps a played note (0-127) to the note to sound, always within 0-127.
+fn map_note(
+    note: u8,
+    mode: SoloMode,
+    chord: &Chord,
+    scale: &ScaleMap,
+    snap: Snap,
+    bounds: Bounds,
+) -> u8 {
+    let note = note as i16;
+    let mapped = match mode {
+        // The arpeggiator and chord pad play chord tones too.
+        SoloMode::Chord | SoloMode::Arpeggio | SoloMode::ChordPad => {
+            let chord_notes = chord
+                .notes()
+                .iter()
+                .map(|n| n.to_i16().unwrap())
+                .collect::<Vec<_>>();
+            [0, -1, 1, -2, 2]
+                .into_iter()
+                .map(|offset| note + offset)
+                .filter(|n| (0..=MAX_NOTE).contains(n))
+                .find(|n| chord_notes.contains(&(n % 12)))
+                .unwrap_or(note)
+        }
+        SoloMode::Harmonize => note,
+        SoloMode::Nearest | SoloMode::Transpose => {
+            let octave = note / 12 * 12;
+            let interval = if mode == SoloMode::Transpose {
+                note % 12
+            } else {
+                scale.snap(note % 12, snap)
+            };
+            octave + interval + scale.tonic as i16
+        }
+    };
+    bounds.apply(mapped)
+}
+
+#[test]
+fn maps_every_note_into_range() {
+    use crate::model::{num_to_chord, NUM_CHORDS};
+    let scales = Scales::load(None);
+    let modes = [SoloMode::Chord, SoloMode::Nearest, SoloMode::Transpose];
+    for (mode, bounds, snap) in itertools::iproduct!(
+        modes,
+        [Bounds::Clamp, Bounds::Fold],
+        [Snap::Down, Snap::Up, Snap::Nearest]
+    ) {
+        for root in 0..12u8 {
+            let key = ModalKey {
+                root: root.into(),
+                mode: ScaleMode::Lydian,
+            };
+            for scale in [
+                ScaleMap::new(&key, None),
+                ScaleMap::new(&key, scales.get("blues")),
+            ] {
+                for chord in (0..NUM_CHORDS).map(num_to_chord) {
+                    for note in 0..=127 {
+                        let mapped = map_note(note, mode, &chord, &scale, snap, bounds);
+                        assert!(mapped <= 127, "{note} -> {mapped} in {mode:?}");
+                        // Folding keeps what transposition meant.
+                        if bounds == Bounds::Fold && mode == SoloMode::Transpose {
+                            assert_eq!(mapped % 12, (note + root) % 12);
+                        }
+                        if mode == SoloMode::Chord {
+                            assert!((mapped as i16 - note as i16).abs() <= 2);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    let c = num_to_chord(0);
+    let b: ModalKey = "B".parse().unwrap();
+    let b = ScaleMap::new(&b, None);
+    let map = |note, mode, bounds| map_note(note, mode, &c, &b, Snap::Nearest, bounds);
+    assert_eq!(map(0, SoloMode::Chord, Bounds::Fold), 0);
+    assert_eq!(map(1, SoloMode::Chord, Bounds::Fold), 0);
+    // G9 up a major seventh: clamped to G9, or folded down an octave.
+    assert_eq!(map(127, SoloMode::Transpose, Bounds::Clamp), 127);
+    assert_eq!(map(127, SoloMode::Transpose, Bounds::Fold), 126);
+}
+
+#[test]
+fn snaps_to_scales_of_the_key() {
+    let a_minor: ModalKey = "Am".parse().unwrap();
+    let scales = Scales::load(None);
+    let chord = crate::model::num_to_chord(19);
+    let snapped = |scale: &ScaleMap, snap| {
+        (60..72)
+            .map(|note| map_note(note, SoloMode::Nearest, &chord, scale, snap, Bounds::Fold))
+            .collect::<Vec<_>>()
+    };
+    // Middle C plays the tonic, and with the mode's own scale the white keys play A aeolian.
+    let aeolian = ScaleMap::new(&a_minor, None);
+    assert_eq!(
+        snapped(&aeolian, Snap::Nearest),
+        [69, 71, 71, 72, 74, 74, 76, 76, 77, 79, 79, 81]
+    );
+    // The blues scale on A: A C D Eb E G.
+    let blues = ScaleMap::new(&a_minor, scales.get("blues"));
+    assert_eq!(
+        snapped(&blues, Snap::Down),
+        [69, 69, 69, 72, 72, 74, 75, 76, 76, 76, 79, 79]
+    );
+    assert_eq!(
+        snapped(&blues, Snap::Up),
+        [69, 72, 72, 72, 74, 74, 75, 76, 79, 79, 79, 81]
+    );
+    // The same mapping as the old hard-coded major table.
+    let c_major = ScaleMap::new(&"C".parse().unwrap(), None);
+    assert_eq!(
+        snapped(&c_major, Snap::Nearest),
+        [60, 62, 62, 64, 64, 65, 67, 67, 69, 69, 71, 71]
+    );
+    // Degree chords: D plays the second degree's triad, B diminished in A aeolian.
+    assert_eq!(c_major.triad(62, Snap::Nearest), [2, 5, 9]);
+    assert_eq!(aeolian.triad(62, Snap::Nearest), [11, 2, 5]);
+    assert_eq!(blues.triad(61, Snap::Down), [9, 2, 4]);
+}
+
+#[test]
+fn harmonizes_in_thirds_and_chord_tones() {
+    let c = crate::model::num_to_chord(0);
+    let c_major = ScaleMap::new(&"C".parse().unwrap(), None);
+    let voices = |interval: &str, voices, note| {
+        let harmony = Harmony {
+            interval: interval.parse().unwrap(),
+            voices,
+            channel: None,
+        };
+        harmonize(note, harmony, &c, &c_major, Bounds::Fold)
+    };
+    assert_eq!(voices("-3", 1, 64), [60]);
+    assert_eq!(voices("-3", 1, 60), [57]);
+    // C sharp is off the scale, and harmonizes like C.
+    assert_eq!(voices("-3", 1, 61), [57]);
+    assert_eq!(voices("-6", 1, 64), [55]);
+    assert_eq!(voices("3", 2, 60), [64, 67]);
+    assert_eq!(voices("chord", 2, 62), [64, 67]);
+    assert_eq!(voices("-chord", 2, 60), [55, 52]);
+    // A third above G9 folds back down an octave.
+    assert_eq!(voices("3", 1, 127), [119]);
+    for bad in ["1", "16", "chords", ""] {
+        assert!(bad.parse::<Interval>().is_err());
+    }
+}
@@ -0,0 +1,355 @@
+use serde::Serialize;
+
+/// A MIDI 1.0 message. Channels count from 0, and a note on with velocity 0 is a note off.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MidiMessage {
+    NoteOff {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    NoteOn {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    PolyPressure {
+        channel: u8,
+        note: u8,
+        pressure: u8,
+    },
+    ControlChange {
+        channel: u8,
+        controller: u8,
+        value: u8,
+    },
+    ProgramChange {
+        channel: u8,
+        program: u8,
+    },
+    ChannelPressure {
+        channel: u8,
+        pressure: u8,
+    },
+    /// 14 bits, centred on 8192.
+    PitchBend {
+        channel: u8,
+        value: u16,
+    },
+    /// Clock, start, stop and the like: a single status byte from 0xf8 up.
+    RealTime(u8),
+    /// Song position, song select, tune request, MIDI time code quarter frames.
+    SystemCommon {
+        status: u8,
+        data: [u8; 2],
+    },
+}
+impl MidiMessage {
+    /// Decodes a message from its status byte and data bytes, which must be complete.
+    fn from_parts(status: u8, data: [u8; 2]) -> Option<Self> {
+        let channel = status & 0x0f;
+        let [a, b] = data.map(|d| d & 0x7f);
+        Some(match status & 0xf0 {
+            0x80 => MidiMessage::NoteOff {
+                channel,
+                note: a,
+                velocity: b,
+            },
+            0x90 if b == 0 => MidiMessage::NoteOff {
+                channel,
+                note: a,
+                velocity: 0,
+            },
+            0x90 => MidiMessage::NoteOn {
+                channel,
+                note: a,
+                velocity: b,
+            },
+            0xa0 => MidiMessage::PolyPressure {
+                channel,
+                note: a,
+                pressure: b,
+            },
+            0xb0 => MidiMessage::ControlChange {
+                channel,
+                controller: a,
+                value: b,
+            },
+            0xc0 => MidiMessage::ProgramChange {
+                channel,
+                program: a,
+            },
+            0xd0 => MidiMessage::ChannelPressure {
+                channel,
+                pressure: a,
+            },
+            0xe0 => MidiMessage::PitchBend {
+                channel,
+                value: a as u16 | ((b as u16) << 7),
+            },
+            _ => match status {
+                0xf8.. => MidiMessage::RealTime(status),
+                0xf1..=0xf3 | 0xf6 => MidiMessage::SystemCommon {
+                    status,
+                    data: [a, b],
+                },
+                _ => return None,
+            },
+        })
+    }
+    /// The message as a MIDI 1.0 byte stream, with an explicit status byte.
+    pub(crate) fn to_bytes(self) -> Vec<u8> {
+        let status = |kind: u8, channel: u8| kind | (channel & 0x0f);
+        match self {
+            MidiMessage::NoteOff {
+                channel,
+                note,
+                velocity,
+            } => vec![status(0x80, channel), note & 0x7f, velocity & 0x7f],
+            MidiMessage::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => vec![status(0x90, channel), note & 0x7f, velocity & 0x7f],
+            MidiMessage::PolyPressure {
+                channel,
+                note,
+                pressure,
+            } => vec![status(0xa0, channel), note & 0x7f, pressure & 0x7f],
+            MidiMessage::ControlChange {
+                channel,
+                controller,
+                value,
+            } => vec![status(0xb0, channel), controller & 0x7f, value & 0x7f],
+            MidiMessage::ProgramChange { channel, program } => {
+                vec![status(0xc0, channel), program & 0x7f]
+            }
+            MidiMessage::ChannelPressure { channel, pressure } => {
+                vec![status(0xd0, channel), pressure & 0x7f]
+            }
+            MidiMessage::PitchBend { channel, value } => vec![
+                status(0xe0, channel),
+                (value & 0x7f) as u8,
+                ((value >> 7) & 0x7f) as u8,
+            ],
+            MidiMessage::RealTime(status) => vec![status],
+            MidiMessage::SystemCommon { status, data } => {
+                let mut bytes = vec![status];
+                bytes.extend(&data[..data_length(status)]);
+                bytes
+            }
+        }
+    }
+}
+
+/// Number of data bytes following `status`, for statuses that take a fixed number.
+fn data_length(status: u8) -> usize {
+    match status {
+        0x80..=0xbf | 0xe0..=0xef | 0xf2 => 2,
+        0xc0..=0xdf | 0xf1 | 0xf3 => 1,
+        _ => 0,
+    }
+}
+
+/// Incremental parser for MIDI 1.0 byte streams. Handles running status and real-time messages
+/// interleaved with other messages, and skips system exclusive messages and stray data bytes.
+#[derive(Default)]
+pub(crate) struct ByteParser {
+    /// The status byte in effect for the data bytes to come.
+    status: Option<u8>,
+    data: [u8; 2],
+    received: usize,
+    in_sysex: bool,
+}
+impl ByteParser {
+    pub(crate) fn push(&mut self, byte: u8) -> Option<MidiMessage> {
+        match byte {
+            0xf8.. => MidiMessage::from_parts(byte, [0, 0]),
+            0xf0 => {
+                self.in_sysex = true;
+                self.status = None;
+                None
+            }
+            0xf7 => {
+                self.in_sysex = false;
+                None
+            }
+            0x80.. => {
+                self.in_sysex = false;
+                self.received = 0;
+                // System common messages cancel running status; those without data are done.
+                self.status = Some(byte);
+                if data_length(byte) == 0 {
+                    self.status = None;
+                    return MidiMessage::from_parts(byte, [0, 0]);
+                }
+                None
+            }
+            _ => {
+                let status = self.status.filter(|_| !self.in_sysex)?;
+                self.data[self.received] = byte;
+                self.received += 1;
+                if self.received < data_length(status) {
+                    return None;
+                }
+                self.received = 0;
+                if status >= 0xf0 {
+                    self.status = None;
+                }
+                MidiMessage::from_parts(status, std::mem::take(&mut self.data))
+            }
+        }
+    }
+    pub(crate) fn parse(&mut self, bytes: &[u8]) -> Vec<MidiMessage> {
+        bytes.iter().filter_map(|&byte| self.push(byte)).collect()
+    }
+}
+
+/// Number of 32-bit words in a Universal MIDI Packet, by message type.
+fn ump_words(message_type: u8) -> usize {
+    match message_type {
+        0x0..=0x2 | 0x6 | 0x7 => 1,
+        0x3 | 0x4 | 0x8..=0xa => 2,
+        0xb | 0xc => 3,
+        _ => 4,
+    }
+}
+
+/// Decodes the MIDI 1.0 messages in a sequence of Universal MIDI Packets, ignoring groups. MIDI
+/// 2.0 channel voice messages are scaled down to MIDI 1.0 resolution; utility, system exclusive
+/// and other packets are skipped.
+pub(crate) fn parse_ump(words: &[u32]) -> Vec<MidiMessage> {
+    let mut messages = vec![];
+    let mut i = 0;
+    while i < words.len() {
+        let word = words[i];
+        let message_type = (word >> 28) as u8;
+        let length = ump_words(message_type);
+        let [_, status, a, b] = word.to_be_bytes();
+        let message = match (message_type, words.get(i + 1)) {
+            // MIDI 1.0 packets carry the bytes of a single message.
+            (0x1 | 0x2, _) => ByteParser::default()
+                .parse(&[status, a, b][..=data_length(status)])
+                .pop(),
+            (0x4, Some(&data)) => midi2_channel_voice(status, a, data),
+            _ => None,
+        };
+        messages.extend(message);
+        i += length;
+    }
+    messages
+}
+
+fn midi2_channel_voice(status: u8, index: u8, data: u32) -> Option<MidiMessage> {
+    let seven = (data >> 25) as u8;
+    match status & 0xf0 {
+        0x80 | 0xa0 | 0xb0 => MidiMessage::from_parts(status, [index, seven]),
+        // A 16-bit velocity too small for 7 bits is still a note on.
+        0x90 => MidiMessage::from_parts(status, [index, seven.max(1)]),
+        0xc0 => MidiMessage::from_parts(status, [(data >> 24) as u8, 0]),
+        0xd0 => MidiMessage::from_parts(status, [seven, 0]),
+        0xe0 => {
+            let value = (data >> 18) as u16;
+            MidiMessage::from_parts(status, [(value & 0x7f) as u8, (value >> 7) as u8])
+        }
+        _ => None,
+    }
+}
+
+#[test]
+fn parses_byte_streams() {
+    let mut parser = ByteParser::default();
+    // Running status, a clock in the middle of a message, and note on with velocity 0.
+    let messages = parser.parse(&[0x91, 60, 100, 62, 0xf8, 90, 60, 0]);
+    assert_eq!(
+        messages,
+        vec![
+            MidiMessage::NoteOn {
+                channel: 1,
+                note: 60,
+                velocity: 100
+            },
+            MidiMessage::RealTime(0xf8),
+            MidiMessage::NoteOn {
+                channel: 1,
+                note: 62,
+                velocity: 90
+            },
+            MidiMessage::NoteOff {
+                channel: 1,
+                note: 60,
+                velocity: 0
+            },
+        ]
+    );
+    // System exclusive is skipped, and cancels running status.
+    assert!(parser.parse(&[0xf0, 0x7e, 0x01, 0xf7, 64, 64]).is_empty());
+    let messages = parser.parse(&[0xe3, 0x00, 0x40, 0xc3, 5, 0xb3, 64, 127, 0xd3, 30]);
+    assert_eq!(
+        messages,
+        vec![
+            MidiMessage::PitchBend {
+                channel: 3,
+                value: 8192
+            },
+            MidiMessage::ProgramChange {
+                channel: 3,
+                program: 5
+            },
+            MidiMessage::ControlChange {
+                channel: 3,
+                controller: 64,
+                value: 127
+            },
+            MidiMessage::ChannelPressure {
+                channel: 3,
+                pressure: 30
+            },
+        ]
+    );
+    for message in messages {
+        assert_eq!(parser.parse(&message.to_bytes()), vec![message]);
+    }
+}
+
+#[test]
+fn parses_universal_midi_packets() {
+    let words = [
+        // Timing clock, then a MIDI 1.0 note on and note on with velocity 0.
+        0x10f80000, 0x20923c64, 0x20923c00,
+        // A two-word system exclusive packet is skipped whole.
+        0x30020000, 0x20b24000,
+        // MIDI 2.0 note on with a tiny velocity, and a full pitch bend up.
+        0x40923c00, 0x00010000, 0x40e20000, 0xffffffff, 0x20a53c22,
+    ];
+    assert_eq!(
+        parse_ump(&words),
+        vec![
+            MidiMessage::RealTime(0xf8),
+            MidiMessage::NoteOn {
+                channel: 2,
+                note: 60,
+                velocity: 100
+            },
+            MidiMessage::NoteOff {
+                channel: 2,
+                note: 60,
+                velocity: 0
+            },
+            MidiMessage::NoteOn {
+                channel: 2,
+                note: 60,
+                velocity: 1
+            },
+            MidiMessage::PitchBend {
+                channel: 2,
+                value: 16383
+            },
+            MidiMessage::PolyPressure {
+                channel: 5,
+                note: 60,
+                pressure: 0x22
+            },
+        ]
+    );
+}
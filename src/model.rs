@@ -1,4 +1,5 @@
 use std::fmt::{Debug, Display};
+use std::path::Path;
 
 use chords::{Chord, ChordBuilder, ChordType};
 use itertools::Itertools;
@@ -6,79 +7,138 @@ use nalgebra::{Const, SMatrix, SVector};
 use nalgebra_mvn::MultivariateNormal;
 use num::{Float, ToPrimitive};
 use ordered_float::OrderedFloat;
+use serde::{Deserialize, Deserializer, Serialize};
 use strum::EnumCount;
+
+use crate::emission::Emission;
 const NUM_NOTES: usize = chords::Note::COUNT;
 pub(crate) const NUM_CHORDS: usize = NUM_NOTES * 2;
 pub(crate) struct Model {
-    gaussians: [MVGaussian; NUM_CHORDS],
+    emission: Box<dyn Emission>,
     hmm_params: HMMParams,
+    adaptation: Option<Adaptation>,
 }
 impl Model {
-    pub(crate) fn infer_viterbi(&self, observations: &[Observation]) -> Vec<Chord> {
-        let mut viterbi = SMatrix::<f32, NUM_CHORDS, NUM_CHORDS>::zeros();
-        let mut backpointer = SMatrix::<usize, NUM_CHORDS, NUM_CHORDS>::zeros();
-        // Initialize viterbi and backpointer
-        for i in 0..NUM_CHORDS {
-            viterbi[(0, i)] = self.hmm_params.log_initial[i]
-                + self
-                    .gaussians
-                    .get(i)
-                    .unwrap()
-                    .log_pdf(observations.get(0).unwrap());
-            backpointer[(0, i)] = 0;
+    pub(crate) fn new(params: &ModelParams) -> Self {
+        Self::with_emission(params, Box::new(Gaussians::new(params)))
+    }
+    /// A model scoring observations with `emission` instead of the Gaussians in `params`.
+    pub(crate) fn with_emission(params: &ModelParams, emission: Box<dyn Emission>) -> Self {
+        Self {
+            emission,
+            hmm_params: params
+                .transitions
+                .as_ref()
+                .map(HMMParams::from)
+                .unwrap_or_default(),
+            adaptation: None,
         }
-        for t in 1..observations.len() {
-            for i in 0..NUM_CHORDS {
-                let mut max = f32::NEG_INFINITY;
-                let mut argmax = 0;
-                for j in 0..NUM_CHORDS {
-                    let value = viterbi[(t - 1, j)]
-                        + self.hmm_params.log_transition.get((j, i)).unwrap()
-                        + self
-                            .gaussians
-                            .get(i)
-                            .unwrap()
-                            .log_pdf(observations.get(t).unwrap());
-                    if value > max {
-                        max = value;
-                        argmax = j;
-                    }
-                }
-                viterbi[(t, i)] = max;
-                backpointer[(t, i)] = argmax;
-            }
+    }
+    /// Turns on online adaptation of the transition matrix towards the transitions heard in this
+    /// session. `forgetting` decays old evidence per observation; `strength` is how many
+    /// observations of a chord it takes for the session to outweigh the prior.
+    pub(crate) fn enable_adaptation(&mut self, forgetting: f32, strength: f32) {
+        self.adaptation = Some(Adaptation {
+            forgetting,
+            strength,
+            prior: self.hmm_params.log_transition.map(f32::exp),
+            counts: MChords::zeros(),
+        });
+    }
+    /// Replaces the transition prior, e.g. with a style preset, and forgets any adaptation.
+    pub(crate) fn set_transitions(&mut self, transitions: Option<&TransitionParams>) {
+        self.hmm_params = transitions.map(HMMParams::from).unwrap_or_default();
+        if let Some(adaptation) = &mut self.adaptation {
+            adaptation.prior = self.hmm_params.log_transition.map(f32::exp);
+            adaptation.counts = MChords::zeros();
         }
-        // println!("{viterbi}");
-        let mut max = f32::NEG_INFINITY;
-        let mut argmax = 0;
-        for i in 0..NUM_CHORDS {
-            if viterbi[(observations.len() - 1, i)] > max {
-                max = viterbi[(observations.len() - 1, i)];
-                argmax = i;
-            }
+    }
+    pub(crate) fn reset_adaptation(&mut self) {
+        if let Some(adaptation) = &mut self.adaptation {
+            adaptation.counts = MChords::zeros();
+            self.hmm_params.log_transition = adaptation.prior.map(f32::ln);
         }
-        let mut result = Vec::with_capacity(observations.len());
-        result.push(num_to_chord(argmax));
-        for t in (1..observations.len()).rev() {
-            result.push(num_to_chord(backpointer[(t, argmax)]));
-            argmax = backpointer[(t, argmax)];
+    }
+    /// One online EM step, using the filtered posterior of the transition into the last
+    /// (completed) observation as the expected count.
+    pub(crate) fn adapt(&mut self, observations: &[Observation]) {
+        if self.adaptation.is_none() || observations.len() < 2 {
+            return;
         }
-        result.reverse();
-        result
+        let previous = &observations[..observations.len() - 1];
+        let alpha = *self.log_forward(previous).last().unwrap();
+        let emissions = self.log_emissions(observations);
+        let mut xi = MChords::from_fn(|i, j| {
+            alpha[i] + self.hmm_params.log_transition[(i, j)] + emissions[j]
+        });
+        let norm = log_sum_exp(xi.iter().copied());
+        xi.iter_mut().for_each(|f| *f = (*f - norm).exp());
+        let adaptation = self.adaptation.as_mut().unwrap();
+        self.hmm_params.log_transition = adaptation.update(&xi);
+    }
+    pub(crate) fn log_initial(&self) -> &VChords {
+        &self.hmm_params.log_initial
+    }
+    pub(crate) fn log_transition(&self) -> &MChords {
+        &self.hmm_params.log_transition
+    }
+    /// Log emission scores for the last of `context`, the observations so far.
+    pub(crate) fn log_emissions(&self, context: &[Observation]) -> VChords {
+        self.emission.log_emissions(context)
+    }
+    /// Log forward variables, normalized at each step so they are filtered log posteriors.
+    fn log_forward(&self, observations: &[Observation]) -> Vec<VChords> {
+        let mut alphas: Vec<VChords> = Vec::with_capacity(observations.len());
+        for t in 0..observations.len() {
+            let emissions = self.log_emissions(&observations[..=t]);
+            let mut alpha = match alphas.last() {
+                None => self.hmm_params.log_initial + emissions,
+                Some(previous) => VChords::from_fn(|j, _| {
+                    log_sum_exp(
+                        (0..NUM_CHORDS)
+                            .map(|i| previous[i] + self.hmm_params.log_transition[(i, j)]),
+                    ) + emissions[j]
+                }),
+            };
+            let norm = log_sum_exp(alpha.iter().copied());
+            alpha.add_scalar_mut(-norm);
+            alphas.push(alpha);
+        }
+        alphas
     }
 }
 impl Default for Model {
     fn default() -> Self {
-        Self {
-            gaussians: chords()
-                .into_iter()
-                .map_into()
-                .collect_vec()
-                .try_into()
-                .unwrap(),
-            hmm_params: HMMParams::default(),
+        Self::new(&ModelParams::default())
+    }
+}
+
+struct Adaptation {
+    forgetting: f32,
+    strength: f32,
+    prior: MChords,
+    counts: MChords,
+}
+impl Adaptation {
+    /// Folds in expected transition counts and returns the MAP log transition matrix, with the
+    /// prior acting as `strength` pseudo-observations per row.
+    fn update(&mut self, xi: &MChords) -> MChords {
+        self.counts = self.counts * self.forgetting + xi;
+        let mut transition = self.prior * self.strength + self.counts;
+        for mut row in transition.row_iter_mut() {
+            row /= row.sum();
         }
+        transition.map(f32::ln)
+    }
+}
+
+pub(crate) fn log_sum_exp(values: impl IntoIterator<Item = f32>) -> f32 {
+    let values = values.into_iter().collect_vec();
+    let max = values.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    if max == f32::NEG_INFINITY {
+        return max;
     }
+    max + values.iter().map(|v| (v - max).exp()).sum::<f32>().ln()
 }
 fn chords() -> impl Iterator<Item = Chord> {
     chords::Note::vec()
@@ -91,65 +151,262 @@ fn chords() -> impl Iterator<Item = Chord> {
                 .build()
         })
 }
-type VNotes = SVector<f32, NUM_NOTES>;
-type MNotes = SMatrix<f32, NUM_NOTES, NUM_NOTES>;
-type VChords = SVector<f32, NUM_CHORDS>;
-type MChords = SMatrix<f32, NUM_CHORDS, NUM_CHORDS>;
+pub(crate) type VNotes = SVector<f32, NUM_NOTES>;
+pub(crate) type MNotes = SMatrix<f32, NUM_NOTES, NUM_NOTES>;
+pub(crate) type VChords = SVector<f32, NUM_CHORDS>;
+pub(crate) type MChords = SMatrix<f32, NUM_CHORDS, NUM_CHORDS>;
 pub(crate) type Observation = VNotes;
+/// A Gaussian mixture over root-rotated chroma; a single component is a plain Gaussian.
 #[derive(Debug)]
 struct MVGaussian {
-    mvn: MultivariateNormal<f32, Const<NUM_NOTES>>,
+    components: Vec<(f32, MultivariateNormal<f32, Const<NUM_NOTES>>)>,
     chord: Chord,
 }
 impl MVGaussian {
+    fn new(chord: Chord, params: &ModelParams) -> Self {
+        let mixture = match chord.chord_type {
+            ChordType::Major => &params.major,
+            ChordType::Minor => &params.minor,
+            _ => unreachable!("Only major and minor chords are supported"),
+        };
+        Self {
+            components: mixture
+                .iter()
+                .map(|gaussian| {
+                    (
+                        gaussian.weight.ln(),
+                        nalgebra_mvn::MultivariateNormal::from_mean_and_covariance(
+                            &gaussian.mean(),
+                            &gaussian.cov(),
+                        )
+                        .expect("Model parameters are validated before use"),
+                    )
+                })
+                .collect(),
+            chord,
+        }
+    }
     fn log_pdf(&self, observation: &Observation) -> f32 {
-        let mut observation = *observation;
-        observation
-            .column_mut(0)
-            .data
-            .into_slice_mut()
-            .rotate_left(self.chord.root.to_usize().unwrap());
-        self.mvn
-            .logpdf(&observation.fixed_resize::<NUM_NOTES, 1>(0.0).transpose())[(0, 0)]
-            .clamp(-1e10, 1e10)
+        let observation = rotate_to_root(observation, self.chord.root.to_usize().unwrap())
+            .fixed_resize::<NUM_NOTES, 1>(0.0)
+            .transpose();
+        log_sum_exp(
+            self.components
+                .iter()
+                .map(|(log_weight, mvn)| log_weight + mvn.logpdf(&observation)[(0, 0)]),
+        )
+        .clamp(-1e10, 1e10)
     }
 }
 
-impl From<Chord> for MVGaussian {
-    fn from(chord: Chord) -> Self {
-        match chord.chord_type {
-            ChordType::Major => Self {
-                mvn: nalgebra_mvn::MultivariateNormal::from_mean_and_covariance(
-                    &MAJOR_MEANS,
-                    &MAJOR_COV,
-                )
-                .unwrap(),
-                chord,
-            },
-            ChordType::Minor => Self {
-                mvn: nalgebra_mvn::MultivariateNormal::from_mean_and_covariance(
-                    &MINOR_MEANS,
-                    &MINOR_COV,
-                )
+/// The Gaussian (mixture) of every chord, built from the per-quality parameters.
+struct Gaussians([MVGaussian; NUM_CHORDS]);
+impl Gaussians {
+    fn new(params: &ModelParams) -> Self {
+        Self(
+            chords()
+                .map(|chord| MVGaussian::new(chord, params))
+                .collect_vec()
+                .try_into()
                 .unwrap(),
-                chord,
-            },
-            _ => unreachable!("Only major and minor chords are supported"),
+        )
+    }
+}
+impl Emission for Gaussians {
+    fn log_emissions(&self, context: &[Observation]) -> VChords {
+        let observation = context.last().unwrap();
+        VChords::from_fn(|i, _| self.0[i].log_pdf(observation))
+    }
+}
+
+/// Rotates a chroma vector so that `root` lands on C, the frame the Gaussians are fit in.
+pub(crate) fn rotate_to_root(observation: &Observation, root: usize) -> Observation {
+    let mut observation = *observation;
+    observation
+        .column_mut(0)
+        .data
+        .into_slice_mut()
+        .rotate_left(root);
+    observation
+}
+
+/// One mixture component. The weights of a quality's components sum to 1.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct GaussianParams {
+    #[serde(default = "unit_weight")]
+    pub(crate) weight: f32,
+    pub(crate) mean: Vec<f32>,
+    pub(crate) cov: Vec<Vec<f32>>,
+}
+impl GaussianParams {
+    pub(crate) fn mean(&self) -> VNotes {
+        VNotes::from_vec(self.mean.clone())
+    }
+    pub(crate) fn cov(&self) -> MNotes {
+        MNotes::from_vec(self.cov.iter().flatten().copied().collect())
+    }
+}
+fn unit_weight() -> f32 {
+    1.0
+}
+
+/// Reads either a list of mixture components or, as older model files have, a single Gaussian.
+fn mixture<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<GaussianParams>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Mixture {
+        Components(Vec<GaussianParams>),
+        Single(GaussianParams),
+    }
+    Ok(match Mixture::deserialize(deserializer)? {
+        Mixture::Components(components) => components,
+        Mixture::Single(gaussian) => vec![gaussian],
+    })
+}
+
+/// Transition and initial probabilities (not logs), indexed like `num_to_chord`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct TransitionParams {
+    pub(crate) initial: Vec<f32>,
+    pub(crate) transition: Vec<Vec<f32>>,
+}
+
+/// Everything a trained model needs, as stored in a model file.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct ModelParams {
+    #[serde(deserialize_with = "mixture")]
+    pub(crate) major: Vec<GaussianParams>,
+    #[serde(deserialize_with = "mixture")]
+    pub(crate) minor: Vec<GaussianParams>,
+    #[serde(default)]
+    pub(crate) transitions: Option<TransitionParams>,
+}
+impl ModelParams {
+    pub(crate) fn load(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read model file {}: {e}", path.display()))?;
+        let params: Self = serde_json::from_str(&contents)
+            .map_err(|e| format!("Failed to parse model file {}: {e}", path.display()))?;
+        params
+            .validate()
+            .map_err(|e| format!("Invalid model file {}: {e}", path.display()))?;
+        Ok(params)
+    }
+    /// Checks that every quality has components with usable weights, means and covariances, and
+    /// that any transitions cover every chord.
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        for (name, mixture) in [("major", &self.major), ("minor", &self.minor)] {
+            if mixture.is_empty() {
+                return Err(format!("{name} has no components"));
+            }
+            for (i, gaussian) in mixture.iter().enumerate() {
+                if !(gaussian.weight > 0.0 && gaussian.weight.is_finite()) {
+                    return Err(format!(
+                        "{name} component {i} has weight {}",
+                        gaussian.weight
+                    ));
+                }
+                if gaussian.mean.len() != NUM_NOTES
+                    || gaussian.cov.len() != NUM_NOTES
+                    || gaussian.cov.iter().any(|row| row.len() != NUM_NOTES)
+                {
+                    return Err(format!(
+                        "{name} component {i} is not {NUM_NOTES}-dimensional"
+                    ));
+                }
+                MultivariateNormal::from_mean_and_covariance(&gaussian.mean(), &gaussian.cov())
+                    .map_err(|e| format!("{name} component {i}: {e:?}"))?;
+            }
         }
+        if let Some(transitions) = &self.transitions {
+            if transitions.initial.len() != NUM_CHORDS
+                || transitions.transition.len() != NUM_CHORDS
+                || transitions
+                    .transition
+                    .iter()
+                    .any(|row| row.len() != NUM_CHORDS)
+            {
+                return Err(format!("transitions must cover all {NUM_CHORDS} chords"));
+            }
+        }
+        Ok(())
+    }
+    pub(crate) fn save(&self, path: &Path) {
+        std::fs::write(path, serde_json::to_string(self).unwrap())
+            .unwrap_or_else(|e| panic!("Failed to write model file {}: {e}", path.display()));
+    }
+}
+impl Default for ModelParams {
+    fn default() -> Self {
+        DEFAULT_PARAMS.clone()
     }
 }
 
 #[test]
 fn transition_matrix() {
-    let matrix = HMMParams::default().log_transition;
-    for i in 0..NUM_CHORDS {
-        let row = matrix.row(i).map(|f| f.exp());
-        assert!((row.sum() - 1.0).abs() < 1e-6);
-        for &v in &row {
-            assert!(v >= 0.0);
-            assert!(v < 1.0);
+    let learned = crate::progression::learn_transitions(
+        &[crate::progression::parse_plain("C G Am F C G F C Dm G C")],
+        0.1,
+        0.2,
+        true,
+    );
+    for matrix in [
+        HMMParams::default().log_transition,
+        HMMParams::from(&learned).log_transition,
+    ] {
+        for i in 0..NUM_CHORDS {
+            let row = matrix.row(i).map(|f| f.exp());
+            assert!((row.sum() - 1.0).abs() < 1e-6);
+            for &v in &row {
+                assert!(v >= 0.0);
+                assert!(v < 1.0);
+            }
+        }
+    }
+}
+
+#[test]
+fn adaptation_learns_repeated_progression() {
+    let mut model = Model::default();
+    model.enable_adaptation(0.995, 20.0);
+    let prior = model.hmm_params.log_transition;
+    // C F# Bb Eb, four beats each: changes the default matrix considers unlikely. Each chord's
+    // first beat is blurred by every other note still ringing.
+    let chords = [0, 12, 20, 6];
+    let progression = chords
+        .iter()
+        .flat_map(|&chord| {
+            let change = template_observation(chord) + Observation::repeat(0.3);
+            [change.normalize()]
+                .into_iter()
+                .chain([template_observation(chord); 3])
+        })
+        .collect_vec();
+    let mut observations = vec![];
+    // Posterior of each chord on its blurred first beat, summed over a chorus.
+    let mut confidence = vec![];
+    for _ in 0..3 {
+        let mut total = 0.0;
+        for (t, &o) in progression.iter().enumerate() {
+            observations.push(o);
+            if t % 4 == 0 {
+                let posterior = model
+                    .log_forward(&observations)
+                    .last()
+                    .unwrap()
+                    .map(f32::exp);
+                total += posterior[chords[t / 4]];
+            }
+            model.adapt(&observations);
         }
+        confidence.push(total);
     }
+    assert!(confidence[2] > confidence[0] + 0.1, "{confidence:?}");
+    let adapted = model.hmm_params.log_transition;
+    assert!(adapted[(0, 12)] > prior[(0, 12)]);
+    assert!(adapted[(12, 20)] > prior[(12, 20)]);
+    model.reset_adaptation();
+    assert_eq!(model.hmm_params.log_transition[(0, 12)], prior[(0, 12)]);
 }
 
 #[derive(Debug)]
@@ -180,7 +437,37 @@ impl Default for HMMParams {
     }
 }
 
-fn num_to_chord<N: ToPrimitive>(n: N) -> Chord {
+impl From<&TransitionParams> for HMMParams {
+    fn from(params: &TransitionParams) -> Self {
+        Self {
+            log_initial: VChords::from_iterator(params.initial.iter().map(|p| p.ln())),
+            log_transition: MChords::from_row_iterator(
+                params.transition.iter().flatten().map(|p| p.ln()),
+            ),
+        }
+    }
+}
+
+pub(crate) const NOTE_NAMES: [&str; NUM_NOTES] = [
+    "C", "Db", "D", "Eb", "E", "F", "Gb", "G", "Ab", "A", "Bb", "B",
+];
+
+/// Name of chord `n`, e.g. "C" or "Ebm".
+pub(crate) fn chord_name(n: usize) -> String {
+    let quality = if n % 2 == 1 { "m" } else { "" };
+    format!("{}{quality}", NOTE_NAMES[n / 2])
+}
+
+pub(crate) fn chord_to_num(chord: &Chord) -> usize {
+    let quality = match chord.chord_type {
+        ChordType::Major => 0,
+        ChordType::Minor => 1,
+        _ => unreachable!("Only major and minor chords are supported"),
+    };
+    chord.root.to_usize().unwrap() * 2 + quality
+}
+
+pub(crate) fn num_to_chord<N: ToPrimitive>(n: N) -> Chord {
     let n = n.to_u8().unwrap();
     let root = n / 2;
     let chord_type = match n % 2 {
@@ -195,6 +482,16 @@ fn num_to_chord<N: ToPrimitive>(n: N) -> Chord {
         .unwrap()
 }
 
+/// A normalized chroma vector with exactly the notes of chord `n`.
+#[cfg(test)]
+pub(crate) fn template_observation(n: usize) -> Observation {
+    let mut observation = Observation::zeros();
+    for note in num_to_chord(n).notes() {
+        observation[note.to_usize().unwrap()] = 1.0;
+    }
+    observation.normalize()
+}
+
 #[test]
 fn test_mvn() {
     let chord: Chord = num_to_chord(0);
@@ -202,7 +499,7 @@ fn test_mvn() {
     for note in chord.notes() {
         observation[note.to_usize().unwrap()] = 1.0;
     }
-    let mvn: MVGaussian = chord.into();
+    let mvn = MVGaussian::new(chord, &ModelParams::default());
     let zeros = mvn.log_pdf(&observation);
     let exact = mvn.log_pdf(&observation);
     assert!(exact > zeros);
@@ -239,22 +536,29 @@ fn float_sorted() {
 use lazy_static::lazy_static;
 
 lazy_static! {
-    static ref MAJOR_MEANS: VNotes =
-        VNotes::from_vec(serde_json::from_str(include_str!("../data/maj_mean.json")).unwrap());
-    static ref MINOR_MEANS: VNotes =
-        VNotes::from_vec(serde_json::from_str(include_str!("../data/min_mean.json")).unwrap());
-    static ref MAJOR_COV: MNotes = MNotes::from_vec(
-        serde_json::from_str::<Vec<Vec<f32>>>(include_str!("../data/maj_cov.json"))
-            .unwrap()
-            .into_iter()
-            .flatten()
-            .collect()
-    );
-    static ref MINOR_COV: MNotes = MNotes::from_vec(
-        serde_json::from_str::<Vec<Vec<f32>>>(include_str!("../data/min_cov.json"))
-            .unwrap()
-            .into_iter()
-            .flatten()
-            .collect()
-    );
+    static ref DEFAULT_PARAMS: ModelParams = ModelParams {
+        transitions: None,
+        major: vec![GaussianParams {
+            weight: 1.0,
+            mean: serde_json::from_str(include_str!("../data/maj_mean.json")).unwrap(),
+            cov: serde_json::from_str(include_str!("../data/maj_cov.json")).unwrap(),
+        }],
+        minor: vec![GaussianParams {
+            weight: 1.0,
+            mean: serde_json::from_str(include_str!("../data/min_mean.json")).unwrap(),
+            cov: serde_json::from_str(include_str!("../data/min_cov.json")).unwrap(),
+        }],
+    };
+}
+
+#[test]
+fn rejects_unusable_model_params() {
+    use crate::emission::{build_model, EmissionKind};
+    assert!(ModelParams::default().validate().is_ok());
+    let mut params = ModelParams::default();
+    params.major.clear();
+    assert!(params.validate().is_err());
+    let mut params = ModelParams::default();
+    params.minor[0].cov = vec![vec![0.0; NUM_NOTES]; NUM_NOTES];
+    assert!(build_model(&params, EmissionKind::Gaussian, None).is_err());
 }
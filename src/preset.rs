@@ -0,0 +1,118 @@
+use chords::ChordType;
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::model::{num_to_chord, TransitionParams, NUM_CHORDS};
+use crate::progression::{learn_transitions, parse_plain};
+
+/// Probability left on chords outside a preset's vocabulary, so they stay reachable.
+const OUTSIDE_VOCABULARY: f32 = 1e-6;
+
+/// Style presets for the prior over progressions, each learned from a few idiomatic progressions
+/// in C and pooled over all keys.
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Preset {
+    /// I-V-vi-IV and its rotations
+    Pop,
+    /// Twelve-bar I7-IV7-V7, major chords only
+    Blues,
+    /// ii-V-I with secondary dominants and fast changes
+    Jazz,
+    /// Long chords over a drone, rocking between two neighbours
+    Modal,
+    /// Power chords, read as major, in driving three and four chord loops
+    Punk,
+}
+impl Preset {
+    fn progressions(&self) -> &'static [&'static str] {
+        match self {
+            Preset::Pop => &[
+                "C G Am F C G Am F",
+                "Am F C G Am F C G",
+                "C Am F G C Am F G",
+                "F G C Am F G C",
+                "C F C G C F G C",
+                "C G Am Em F C F G",
+            ],
+            Preset::Blues => &[
+                "C7 C7 C7 C7 F7 F7 C7 C7 G7 F7 C7 G7",
+                "C7 F7 C7 C7 F7 F7 C7 C7 G7 F7 C7 C7",
+                "C7 C7 C7 C7 F7 F7 C7 A7 D7 G7 C7 G7",
+            ],
+            Preset::Jazz => &[
+                "Dm7 G7 Cmaj7 Cmaj7",
+                "Cmaj7 A7 Dm7 G7 Cmaj7",
+                "Em7 A7 Dm7 G7 Cmaj7",
+                "Cmaj7 E7 Am7 D7 Dm7 G7 Cmaj7",
+                "Bm7b5 E7 Am7 D7 Gm7 C7 Fmaj7",
+                "Fmaj7 Fm7 Bb7 Cmaj7 Am7 Dm7 G7",
+            ],
+            Preset::Modal => &["Dm C Dm C", "Dm G Dm G", "C Bb C Bb", "Am G Am", "Em D Em"],
+            Preset::Punk => &[
+                "C5 F5 G5 C5",
+                "C5 G5 A5 F5",
+                "E5 A5 B5 A5",
+                "C5 Bb5 F5 C5",
+                "D5 C5 G5 D5",
+            ],
+        }
+    }
+    /// How likely the chord is to last another beat.
+    fn self_transition(&self) -> f32 {
+        match self {
+            Preset::Pop => 0.3,
+            Preset::Blues => 0.4,
+            Preset::Jazz => 0.15,
+            Preset::Modal => 0.6,
+            Preset::Punk => 0.3,
+        }
+    }
+    fn in_vocabulary(&self, chord: usize) -> bool {
+        match self {
+            Preset::Blues | Preset::Punk => num_to_chord(chord).chord_type == ChordType::Major,
+            _ => true,
+        }
+    }
+    pub(crate) fn transitions(&self) -> TransitionParams {
+        let sequences = self
+            .progressions()
+            .iter()
+            .map(|progression| parse_plain(progression))
+            .collect::<Vec<_>>();
+        let mut params = learn_transitions(&sequences, 0.1, self.self_transition(), true);
+        let weight = |chord| {
+            if self.in_vocabulary(chord) {
+                1.0
+            } else {
+                OUTSIDE_VOCABULARY
+            }
+        };
+        for row in [&mut params.initial]
+            .into_iter()
+            .chain(params.transition.iter_mut())
+        {
+            (0..NUM_CHORDS).for_each(|c| row[c] *= weight(c));
+            let total: f32 = row.iter().sum();
+            row.iter_mut().for_each(|p| *p /= total);
+        }
+        params
+    }
+}
+
+#[test]
+fn presets_follow_their_style() {
+    for preset in Preset::value_variants() {
+        let params = preset.transitions();
+        for row in [&params.initial].into_iter().chain(&params.transition) {
+            assert!((row.iter().sum::<f32>() - 1.0).abs() < 1e-5);
+        }
+    }
+    // From C: pop goes to G rather than D, blues to F rather than D, and jazz rarely stays.
+    let pop = Preset::Pop.transitions().transition;
+    assert!(pop[0][14] > pop[0][4]);
+    let blues = Preset::Blues.transitions().transition;
+    assert!(blues[0][10] > 10.0 * blues[0][4]);
+    assert!(blues[0][19] < 1e-5);
+    let jazz = Preset::Jazz.transitions().transition;
+    assert!(jazz[0][0] < pop[0][0]);
+}
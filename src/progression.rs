@@ -0,0 +1,189 @@
+use std::path::Path;
+
+use crate::model::{TransitionParams, NUM_CHORDS};
+
+const NUM_ROOTS: usize = NUM_CHORDS / 2;
+
+/// Words, symbols and characters chord qualities and extensions are spelled with.
+const QUALITY_PARTS: [&str; 22] = [
+    "minmaj", "mmaj", "maj", "min", "hdim", "dim", "aug", "sus", "add", "alt", "no", "omit", "m",
+    "M", "°", "ø", "+", "#", "b", "(", ")", ",",
+];
+
+/// Maps a chord symbol ("Am", "F#:min7", "Bb/D", "C:maj") onto the model's chord index,
+/// reducing every quality to major or minor. Returns `None` for "N"/"X" and for unparsable
+/// symbols, including unknown qualities.
+pub(crate) fn parse_chord_symbol(symbol: &str) -> Option<usize> {
+    let symbol = symbol.split('/').next()?;
+    let mut chars = symbol.chars();
+    let mut root: usize = match chars.next()? {
+        'C' => 0,
+        'D' => 2,
+        'E' => 4,
+        'F' => 5,
+        'G' => 7,
+        'A' => 9,
+        'B' => 11,
+        _ => return None,
+    };
+    let mut rest = chars.as_str();
+    loop {
+        if let Some(r) = rest.strip_prefix('#') {
+            root += 1;
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix('b') {
+            root += NUM_ROOTS - 1;
+            rest = r;
+        } else {
+            break;
+        }
+    }
+    let quality = rest.trim_start_matches(':');
+    if !is_quality(quality) {
+        return None;
+    }
+    let minor = (quality.starts_with('m') && !quality.starts_with("maj"))
+        || ["dim", "hdim", "°", "ø"]
+            .iter()
+            .any(|prefix| quality.starts_with(prefix));
+    Some((root % NUM_ROOTS) * 2 + minor as usize)
+}
+
+/// Whether `quality` is spelled entirely from quality words, accidentals and degrees.
+fn is_quality(mut quality: &str) -> bool {
+    while !quality.is_empty() {
+        let digits = quality.len()
+            - quality
+                .trim_start_matches(|c: char| c.is_ascii_digit())
+                .len();
+        let part = match QUALITY_PARTS.iter().find(|p| quality.starts_with(*p)) {
+            _ if digits > 0 => digits,
+            Some(part) => part.len(),
+            None => return false,
+        };
+        quality = &quality[part..];
+    }
+    true
+}
+
+/// `parse_chord_symbol` for a token of a chord file, warning about any that is neither a chord
+/// nor a no-chord mark.
+pub(crate) fn parse_chord_token(token: &str) -> Option<usize> {
+    let chord = parse_chord_symbol(token);
+    if chord.is_none() && !matches!(token, "N" | "X") {
+        eprintln!("Skipping unknown chord {token:?}");
+    }
+    chord
+}
+
+/// Reads the chord sequence of a `.lab` file, a ChordPro chart (`.cho`, `.chopro`, `.chordpro`)
+/// or, for any other extension, a plain whitespace/bar-line separated progression.
+pub(crate) fn read_progression(path: &Path) -> Vec<usize> {
+    let contents = std::fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("Failed to read progression {}: {e}", path.display()));
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("lab") => parse_lab(&contents),
+        Some("cho" | "chopro" | "chordpro") => parse_chordpro(&contents),
+        _ => parse_plain(&contents),
+    }
+}
+
+pub(crate) fn parse_lab(contents: &str) -> Vec<usize> {
+    contents
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(2))
+        .filter_map(parse_chord_token)
+        .collect()
+}
+
+pub(crate) fn parse_chordpro(contents: &str) -> Vec<usize> {
+    contents
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('{'))
+        .flat_map(|line| line.split('[').skip(1))
+        .filter_map(|chunk| chunk.split(']').next())
+        .filter_map(parse_chord_token)
+        .collect()
+}
+
+pub(crate) fn parse_plain(contents: &str) -> Vec<usize> {
+    contents
+        .split(|c: char| c.is_whitespace() || c == '|')
+        .filter(|token| !token.is_empty())
+        .filter_map(parse_chord_token)
+        .collect()
+}
+
+/// Estimates transition and initial probabilities from chord sequences.
+///
+/// Only chord changes are counted, since the decoder runs once per beat rather than once per
+/// chord; the self-transition probability is set directly instead. `smoothing`, which must be
+/// positive, is added to every count, and `transpose` pools the counts over all twelve keys.
+pub(crate) fn learn_transitions(
+    sequences: &[Vec<usize>],
+    smoothing: f32,
+    self_transition: f32,
+    transpose: bool,
+) -> TransitionParams {
+    assert!(
+        (0.0..1.0).contains(&self_transition),
+        "Self-transition probability must be in [0, 1)"
+    );
+    // Without it, chords never seen in the sequences would have no transitions at all.
+    assert!(smoothing > 0.0, "Smoothing must be positive");
+    let mut initial = vec![smoothing; NUM_CHORDS];
+    let mut transition = vec![vec![smoothing; NUM_CHORDS]; NUM_CHORDS];
+    let shifts = if transpose { NUM_ROOTS } else { 1 };
+    for sequence in sequences {
+        let mut changes = sequence.clone();
+        changes.dedup();
+        for shift in 0..shifts {
+            let shifted = |chord: usize| (chord + shift * 2) % NUM_CHORDS;
+            if let Some(&first) = changes.first() {
+                initial[shifted(first)] += 1.0;
+            }
+            for pair in changes.windows(2) {
+                transition[shifted(pair[0])][shifted(pair[1])] += 1.0;
+            }
+        }
+    }
+    let total: f32 = initial.iter().sum();
+    initial.iter_mut().for_each(|p| *p /= total);
+    for (i, row) in transition.iter_mut().enumerate() {
+        row[i] = 0.0;
+        let total: f32 = row.iter().sum();
+        row.iter_mut()
+            .for_each(|p| *p *= (1.0 - self_transition) / total);
+        row[i] = self_transition;
+    }
+    TransitionParams {
+        initial,
+        transition,
+    }
+}
+
+#[test]
+fn chord_symbols() {
+    assert_eq!(parse_chord_symbol("C"), Some(0));
+    assert_eq!(parse_chord_symbol("Am"), Some(19));
+    assert_eq!(parse_chord_symbol("A:min7"), Some(19));
+    assert_eq!(parse_chord_symbol("Cmaj7"), Some(0));
+    assert_eq!(parse_chord_symbol("Bb/D"), Some(20));
+    assert_eq!(parse_chord_symbol("Cb"), Some(22));
+    assert_eq!(parse_chord_symbol("F#:hdim7"), Some(13));
+    assert_eq!(parse_chord_symbol("N"), None);
+    assert_eq!(parse_chord_symbol("G7(b9,#11)"), Some(14));
+    assert_eq!(parse_chord_symbol("Dm7b5"), Some(5));
+    assert_eq!(parse_chord_symbol("Am/E"), Some(19));
+    assert_eq!(parse_chord_symbol("Bridge"), None);
+    assert_eq!(parse_chord_symbol("Cx"), None);
+    assert_eq!(
+        parse_lab("0.0 1.2 C:maj\n1.2 2.0 N\n2.0 3.1 G:7\n"),
+        vec![0, 14]
+    );
+    assert_eq!(
+        parse_chordpro("{title: Song}\n[C]Hello [Am]darkness my [F]old friend"),
+        vec![0, 19, 10]
+    );
+    assert_eq!(parse_plain("| C | G | Am | F |"), vec![0, 14, 19, 10]);
+}
@@ -0,0 +1,174 @@
+use std::str::FromStr;
+
+use clap::ValueEnum;
+
+use crate::midi::MidiMessage;
+
+/// Non-note messages that can be kept from reaching the destination.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MessageKind {
+    ControlChange,
+    PitchBend,
+    ChannelPressure,
+    PolyPressure,
+    ProgramChange,
+}
+impl MessageKind {
+    fn of(message: &MidiMessage) -> Option<Self> {
+        match message {
+            MidiMessage::ControlChange { .. } => Some(MessageKind::ControlChange),
+            MidiMessage::PitchBend { .. } => Some(MessageKind::PitchBend),
+            MidiMessage::ChannelPressure { .. } => Some(MessageKind::ChannelPressure),
+            MidiMessage::PolyPressure { .. } => Some(MessageKind::PolyPressure),
+            MidiMessage::ProgramChange { .. } => Some(MessageKind::ProgramChange),
+            _ => None,
+        }
+    }
+}
+
+/// Renumbers controller `from` to `to`, or drops it without `to`. Written `1=74` or `1=`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ControllerRule {
+    from: u8,
+    to: Option<u8>,
+}
+impl FromStr for ControllerRule {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let controller = |c: &str| match c.trim().parse::<u8>() {
+            Ok(c) if c < 128 => Ok(c),
+            _ => Err(format!("Invalid controller number '{c}'")),
+        };
+        let (from, to) = s
+            .split_once('=')
+            .ok_or_else(|| format!("Expected FROM=TO or FROM=, got '{s}'"))?;
+        Ok(Self {
+            from: controller(from)?,
+            to: match to.trim() {
+                "" => None,
+                to => Some(controller(to)?),
+            },
+        })
+    }
+}
+
+/// Rules applied to every message sent to the destination. Notes and other channel messages keep
+/// the controller's channel and values unless a rule says otherwise.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Routing {
+    /// Channel, from 0, for all output.
+    pub(crate) channel: Option<u8>,
+    /// Velocity for every note on, as before dynamics were passed through.
+    pub(crate) velocity: Option<u8>,
+    pub(crate) controllers: Vec<ControllerRule>,
+    pub(crate) blocked: Vec<MessageKind>,
+}
+impl Routing {
+    /// The channel a message sent on `channel` is routed to.
+    pub(crate) fn channel_for(&self, channel: u8, keep_channel: bool) -> u8 {
+        match keep_channel {
+            true => channel,
+            false => self.channel.unwrap_or(channel),
+        }
+    }
+    /// Applies the rules to `message`, all but `channel` if it is to `keep_channel`.
+    pub(crate) fn route(
+        &self,
+        mut message: MidiMessage,
+        keep_channel: bool,
+    ) -> Option<MidiMessage> {
+        if MessageKind::of(&message).is_some_and(|kind| self.blocked.contains(&kind)) {
+            return None;
+        }
+        match &mut message {
+            MidiMessage::NoteOn { velocity, .. } => {
+                *velocity = self.velocity.unwrap_or(*velocity);
+            }
+            MidiMessage::ControlChange { controller, .. } => {
+                if let Some(rule) = self.controllers.iter().find(|r| r.from == *controller) {
+                    *controller = rule.to?;
+                }
+            }
+            _ => {}
+        }
+        match &mut message {
+            MidiMessage::NoteOff { channel, .. }
+            | MidiMessage::NoteOn { channel, .. }
+            | MidiMessage::PolyPressure { channel, .. }
+            | MidiMessage::ControlChange { channel, .. }
+            | MidiMessage::ProgramChange { channel, .. }
+            | MidiMessage::ChannelPressure { channel, .. }
+            | MidiMessage::PitchBend { channel, .. } => {
+                *channel = self.channel_for(*channel, keep_channel);
+            }
+            MidiMessage::RealTime(_) | MidiMessage::SystemCommon { .. } => {}
+        }
+        Some(message)
+    }
+}
+
+#[test]
+fn routes_by_rules() {
+    let note = MidiMessage::NoteOn {
+        channel: 3,
+        note: 60,
+        velocity: 42,
+    };
+    let sustain = MidiMessage::ControlChange {
+        channel: 3,
+        controller: 64,
+        value: 127,
+    };
+    let bend = MidiMessage::PitchBend {
+        channel: 3,
+        value: 9000,
+    };
+    // By default everything passes through unchanged.
+    let routing = Routing::default();
+    for message in [note, sustain, bend] {
+        assert_eq!(routing.route(message, false), Some(message));
+    }
+
+    let routing = Routing {
+        channel: Some(0),
+        velocity: Some(127),
+        controllers: vec!["64=66".parse().unwrap(), "1=".parse().unwrap()],
+        blocked: vec![MessageKind::PitchBend],
+    };
+    assert_eq!(
+        routing.route(note, false),
+        Some(MidiMessage::NoteOn {
+            channel: 0,
+            note: 60,
+            velocity: 127
+        })
+    );
+    assert_eq!(
+        routing.route(sustain, false),
+        Some(MidiMessage::ControlChange {
+            channel: 0,
+            controller: 66,
+            value: 127
+        })
+    );
+    let modulation = MidiMessage::ControlChange {
+        channel: 3,
+        controller: 1,
+        value: 10,
+    };
+    assert_eq!(routing.route(modulation, false), None);
+    assert_eq!(routing.route(bend, false), None);
+    // A harmony voice on a channel of its own keeps it, but a note played there does not.
+    let harmony = MidiMessage::NoteOn {
+        channel: 5,
+        note: 64,
+        velocity: 127,
+    };
+    assert_eq!(routing.route(harmony, true), Some(harmony));
+    assert!(matches!(
+        routing.route(harmony, false),
+        Some(MidiMessage::NoteOn { channel: 0, .. })
+    ));
+    assert!("128=1".parse::<ControllerRule>().is_err());
+    assert!("7".parse::<ControllerRule>().is_err());
+}
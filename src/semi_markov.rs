@@ -0,0 +1,218 @@
+use crate::key::{Key, KeyTransitions};
+use crate::model::{log_sum_exp, Model, Observation, VChords, NUM_CHORDS};
+
+/// Probability mass spread evenly over every length, so that passing chords and odd phrase
+/// lengths stay possible when the evidence for them is strong.
+const FLOOR: f32 = 0.1;
+
+/// Chord duration distribution in beats (one observation per beat), peaking at half a bar and at
+/// one, two and four bars.
+pub(crate) struct Durations {
+    log_pmf: Vec<f32>,
+    /// `log_survival[d - 1]` is the log probability of lasting at least `d` beats.
+    log_survival: Vec<f32>,
+}
+impl Durations {
+    pub(crate) fn new(beats_per_bar: usize) -> Self {
+        let max = beats_per_bar * 4;
+        let mut pmf = vec![FLOOR / max as f32; max];
+        for (bars, weight) in [(0.5, 0.1), (1.0, 0.35), (2.0, 0.35), (4.0, 0.2)] {
+            let beats = ((beats_per_bar as f32 * bars).round() as usize).max(1);
+            pmf[beats - 1] += (1.0 - FLOOR) * weight;
+        }
+        let total: f32 = pmf.iter().sum();
+        pmf.iter_mut().for_each(|p| *p /= total);
+        let log_survival = (0..max)
+            .map(|d| pmf[d..].iter().sum::<f32>().ln())
+            .collect();
+        Self {
+            log_pmf: pmf.iter().map(|p| p.ln()).collect(),
+            log_survival,
+        }
+    }
+    fn max(&self) -> usize {
+        self.log_pmf.len()
+    }
+    /// Log probability of a segment of `d` beats from `start` to `end` of `n` observations. A
+    /// segment cut by either end only has to last at least as long as it was observed.
+    fn score(&self, start: usize, end: usize, n: usize) -> Option<f32> {
+        let d = end - start;
+        match (start == 0, end == n) {
+            (true, true) => Some(0.0),
+            (true, false) | (false, true) => self.log_survival.get(d - 1).copied(),
+            (false, false) => self.log_pmf.get(d - 1).copied(),
+        }
+    }
+}
+
+/// Running sums of each chord's log emissions, so a segment scores in constant time.
+fn cumulative_emissions(model: &Model, observations: &[Observation]) -> Vec<VChords> {
+    let mut cumulative = vec![VChords::zeros()];
+    for t in 0..observations.len() {
+        let emissions = model.log_emissions(&observations[..=t]);
+        cumulative.push(cumulative[t] + emissions);
+    }
+    cumulative
+}
+
+/// Explicit-duration Viterbi over chords, with `keys[t]` the key in force at observation `t`.
+///
+/// Segments cut by either end of `observations` are scored by the probability of lasting at
+/// least as long as they were observed. A chord may follow itself, so chords held longer than the
+/// longest duration are decoded as repeats. Returns a chord index per observation.
+pub(crate) fn decode_segments(
+    model: &Model,
+    observations: &[Observation],
+    keys: &[Key],
+    durations: &Durations,
+    transitions: &KeyTransitions,
+) -> Vec<usize> {
+    let n = observations.len();
+    if n == 0 {
+        return vec![];
+    }
+    let cumulative = cumulative_emissions(model, observations);
+
+    // best[t][c]: best score of `observations[..t]` with a segment of chord `c` ending at t.
+    let mut best = vec![VChords::repeat(f32::NEG_INFINITY); n + 1];
+    let mut pointers = vec![[(0, 0); NUM_CHORDS]; n + 1];
+    for t in 1..=n {
+        for c in 0..NUM_CHORDS {
+            let longest = if t == n { t } else { t.min(durations.max()) };
+            for d in 1..=longest {
+                let start = t - d;
+                let segment = cumulative[t][c] - cumulative[start][c];
+                let Some(duration) = durations.score(start, t, n) else {
+                    continue;
+                };
+                let (score, previous) = if start == 0 {
+                    (model.log_initial()[c] + duration, 0)
+                } else {
+                    let transition = transitions.in_key(keys[start].index());
+                    let (previous, score) = (0..NUM_CHORDS)
+                        .map(|p| (p, best[start][p] + transition[(p, c)]))
+                        .max_by(|a, b| a.1.total_cmp(&b.1))
+                        .unwrap();
+                    (score + duration, previous)
+                };
+                if score + segment > best[t][c] {
+                    best[t][c] = score + segment;
+                    pointers[t][c] = (d, previous);
+                }
+            }
+        }
+    }
+
+    let mut chord = best[n].argmax().0;
+    let mut t = n;
+    let mut path = vec![];
+    while t > 0 {
+        let (d, previous) = pointers[t][chord];
+        path.resize(path.len() + d, chord);
+        t -= d;
+        chord = previous;
+    }
+    path.reverse();
+    path
+}
+
+/// Posterior probability of each chord at each observation under the same model as
+/// `decode_segments`, by forward-backward over segments.
+pub(crate) fn segment_posteriors(
+    model: &Model,
+    observations: &[Observation],
+    keys: &[Key],
+    durations: &Durations,
+    transitions: &KeyTransitions,
+) -> Vec<VChords> {
+    let n = observations.len();
+    let cumulative = cumulative_emissions(model, observations);
+    let segment = |start: usize, end: usize, c: usize| {
+        durations
+            .score(start, end, n)
+            .map(|duration| duration + cumulative[end][c] - cumulative[start][c])
+    };
+    // forward[t][c]: log probability of `observations[..t]` with a segment of `c` ending at t;
+    // starts[t][c]: the same with a segment of `c` starting at t.
+    let mut forward = vec![VChords::repeat(f32::NEG_INFINITY); n + 1];
+    let mut starts = vec![VChords::repeat(f32::NEG_INFINITY); n + 1];
+    starts[0] = *model.log_initial();
+    for t in 1..=n {
+        forward[t] = VChords::from_fn(|c, _| {
+            log_sum_exp((0..t).filter_map(|start| Some(starts[start][c] + segment(start, t, c)?)))
+        });
+        if t < n {
+            let transition = transitions.in_key(keys[t].index());
+            starts[t] = VChords::from_fn(|c, _| {
+                log_sum_exp((0..NUM_CHORDS).map(|p| forward[t][p] + transition[(p, c)]))
+            });
+        }
+    }
+    // backward[t][c]: log probability of `observations[t..]` given a segment of `c` starting at t.
+    let mut backward = vec![VChords::repeat(f32::NEG_INFINITY); n + 1];
+    let mut ends = vec![VChords::repeat(f32::NEG_INFINITY); n + 1];
+    ends[n] = VChords::zeros();
+    for t in (0..n).rev() {
+        backward[t] = VChords::from_fn(|c, _| {
+            log_sum_exp((t + 1..=n).filter_map(|end| Some(segment(t, end, c)? + ends[end][c])))
+        });
+        if t > 0 {
+            let transition = transitions.in_key(keys[t].index());
+            ends[t] = VChords::from_fn(|p, _| {
+                log_sum_exp((0..NUM_CHORDS).map(|c| transition[(p, c)] + backward[t][c]))
+            });
+        }
+    }
+
+    let total = log_sum_exp(forward[n].iter().copied());
+    // Each segment's probability added where it starts and taken away where it ends.
+    let mut changes = vec![VChords::zeros(); n + 1];
+    for start in 0..n {
+        for end in start + 1..=n {
+            for c in 0..NUM_CHORDS {
+                if let Some(score) = segment(start, end, c) {
+                    let p = (starts[start][c] + score + ends[end][c] - total).exp();
+                    changes[start][c] += p;
+                    changes[end][c] -= p;
+                }
+            }
+        }
+    }
+    let mut posterior = VChords::zeros();
+    changes[..n]
+        .iter()
+        .map(|change| {
+            posterior += change;
+            posterior
+        })
+        .collect()
+}
+
+#[test]
+fn snaps_to_bars_but_keeps_passing_chords() {
+    use crate::model::{log_sum_exp, template_observation};
+    use crate::progression::parse_plain;
+    use itertools::Itertools;
+    let durations = Durations::new(4);
+    assert!((log_sum_exp(durations.log_pmf.iter().copied())).abs() < 1e-5);
+    assert!(durations.log_pmf[3] > durations.log_pmf[2]);
+    assert!(durations.log_pmf[7] > durations.log_pmf[6]);
+
+    let model = Model::default();
+    let chords = parse_plain("C C C C G G G G Am Am Am D F F F F");
+    let observations = chords
+        .iter()
+        .map(|&c| template_observation(c))
+        .collect_vec();
+    let keys = vec![Key::from_index(0); observations.len()];
+    let transitions = KeyTransitions::new(&model);
+    assert_eq!(
+        decode_segments(&model, &observations, &keys, &durations, &transitions),
+        chords
+    );
+    let posteriors = segment_posteriors(&model, &observations, &keys, &durations, &transitions);
+    for (posterior, &chord) in posteriors.iter().zip(&chords) {
+        assert!((posterior.sum() - 1.0).abs() < 1e-3);
+        assert_eq!(posterior.argmax().0, chord);
+    }
+}
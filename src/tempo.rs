@@ -0,0 +1,41 @@
+use std::time::{Duration, Instant};
+
+/// Tempo assumed until two beats have been heard, 120 bpm.
+const DEFAULT_BEAT: Duration = Duration::from_millis(500);
+/// Beat intervals outside this range are taken as missed or extra onsets.
+const MIN_BEAT: Duration = Duration::from_millis(200);
+const MAX_BEAT: Duration = Duration::from_millis(2000);
+/// Weight of each new beat interval in the estimate.
+const SMOOTHING: f32 = 0.3;
+
+/// Beat length estimated from the onsets the beat detector hears.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Tempo {
+    pub(crate) beat_length: Duration,
+    last_beat: Option<Instant>,
+}
+impl Default for Tempo {
+    fn default() -> Self {
+        Self {
+            beat_length: DEFAULT_BEAT,
+            last_beat: None,
+        }
+    }
+}
+impl Tempo {
+    /// Whether an onset at `now` comes too soon after the last beat to be the next one.
+    pub(crate) fn off_beat(&self, now: Instant) -> bool {
+        self.last_beat
+            .is_some_and(|last| now - last < self.beat_length / 2)
+    }
+    pub(crate) fn beat(&mut self, now: Instant) {
+        if let Some(last) = self.last_beat {
+            let interval = now - last;
+            if (MIN_BEAT..=MAX_BEAT).contains(&interval) {
+                self.beat_length =
+                    self.beat_length.mul_f32(1.0 - SMOOTHING) + interval.mul_f32(SMOOTHING);
+            }
+        }
+        self.last_beat = Some(now);
+    }
+}
@@ -0,0 +1,238 @@
+use std::path::Path;
+
+use itertools::Itertools;
+use nalgebra::{DMatrix, RowDVector};
+use nalgebra_mvn::MultivariateNormal;
+
+use crate::capture::Samples;
+use crate::model::{log_sum_exp, GaussianParams, MNotes, ModelParams, VNotes};
+
+/// Added to the diagonal of every mixture covariance, so no component collapses onto a few
+/// samples.
+const REGULARIZATION: f32 = 1e-4;
+const MAX_ITERATIONS: usize = 100;
+/// Components responsible for less than this many samples in total are dropped.
+const MIN_RESPONSIBILITY: f32 = 1e-3;
+
+/// Fits a full-covariance Gaussian, or `None` when there are too few samples for a proper covariance.
+pub(crate) fn fit_gaussian(samples: &[Vec<f32>]) -> Option<GaussianParams> {
+    let n = samples.len();
+    let dim = samples.first()?.len();
+    if n <= dim {
+        return None;
+    }
+    let data = DMatrix::from_rows(
+        &samples
+            .iter()
+            .map(|s| RowDVector::from_row_slice(s))
+            .collect::<Vec<_>>(),
+    );
+    let mean = data.row_mean();
+    let centered = DMatrix::from_fn(n, dim, |i, j| data[(i, j)] - mean[j]);
+    let cov = centered.transpose() * &centered / (n - 1) as f32;
+    Some(GaussianParams {
+        weight: 1.0,
+        mean: mean.iter().copied().collect(),
+        cov: cov
+            .row_iter()
+            .map(|row| row.iter().copied().collect())
+            .collect(),
+    })
+}
+
+/// Fits a Gaussian mixture of up to `components` components with EM, or `None` when there are too
+/// few samples for a proper covariance per component or a covariance is not positive definite.
+/// Components left responsible for (almost) no samples are dropped.
+///
+/// Initialized deterministically: the first mean is the sample nearest the overall mean and each
+/// further mean is the sample farthest from the ones chosen so far.
+pub(crate) fn fit_mixture(samples: &[Vec<f32>], components: usize) -> Option<Vec<GaussianParams>> {
+    if components == 1 {
+        return fit_gaussian(samples).map(|gaussian| vec![gaussian]);
+    }
+    let n = samples.len();
+    if components == 0 || n <= components * VNotes::zeros().len() {
+        return None;
+    }
+    let data = samples
+        .iter()
+        .map(|s| VNotes::from_column_slice(s))
+        .collect_vec();
+    let overall: VNotes = data.iter().sum::<VNotes>() / n as f32;
+    let overall_cov = data
+        .iter()
+        .map(|x| (x - overall) * (x - overall).transpose())
+        .sum::<MNotes>()
+        / n as f32;
+
+    let nearest = |to: &VNotes| {
+        data.iter()
+            .min_by(|a, b| {
+                (*a - to)
+                    .norm_squared()
+                    .partial_cmp(&(*b - to).norm_squared())
+                    .unwrap()
+            })
+            .unwrap()
+    };
+    let mut means = vec![*nearest(&overall)];
+    while means.len() < components {
+        let distance = |x: &VNotes| {
+            means
+                .iter()
+                .map(|m| (x - m).norm_squared())
+                .fold(f32::INFINITY, f32::min)
+        };
+        let farthest = data
+            .iter()
+            .max_by(|a, b| distance(a).partial_cmp(&distance(b)).unwrap())
+            .unwrap();
+        means.push(*farthest);
+    }
+    let mut mixture = means
+        .into_iter()
+        .map(|mean| (1.0 / components as f32, mean, regularized(overall_cov)))
+        .collect_vec();
+
+    let mut previous = f32::NEG_INFINITY;
+    for _ in 0..MAX_ITERATIONS {
+        // E step: responsibilities of each component for each sample.
+        let mvns = mixture
+            .iter()
+            .map(|(weight, mean, cov)| {
+                let mvn = MultivariateNormal::from_mean_and_covariance(mean, cov).ok()?;
+                Some((weight.ln(), mvn))
+            })
+            .collect::<Option<Vec<_>>>()?;
+        let mut log_likelihood = 0.0;
+        let responsibilities = data
+            .iter()
+            .map(|x| {
+                let joint = mvns
+                    .iter()
+                    .map(|(log_weight, mvn)| log_weight + mvn.logpdf(&x.transpose())[(0, 0)])
+                    .collect_vec();
+                let total = log_sum_exp(joint.iter().copied());
+                log_likelihood += total;
+                joint.into_iter().map(|j| (j - total).exp()).collect_vec()
+            })
+            .collect_vec();
+
+        // M step.
+        mixture = (0..mixture.len())
+            .filter_map(|k| {
+                let total: f32 = responsibilities.iter().map(|r| r[k]).sum();
+                if total < MIN_RESPONSIBILITY {
+                    return None;
+                }
+                let mean = data
+                    .iter()
+                    .zip(&responsibilities)
+                    .map(|(x, r)| x * r[k])
+                    .sum::<VNotes>()
+                    / total;
+                let cov = data
+                    .iter()
+                    .zip(&responsibilities)
+                    .map(|(x, r)| (x - mean) * (x - mean).transpose() * r[k])
+                    .sum::<MNotes>()
+                    / total;
+                Some((total / n as f32, mean, regularized(cov)))
+            })
+            .collect();
+
+        if log_likelihood - previous < 1e-4 * n as f32 {
+            break;
+        }
+        previous = log_likelihood;
+    }
+    Some(
+        mixture
+            .into_iter()
+            .map(|(weight, mean, cov)| GaussianParams {
+                weight,
+                mean: mean.iter().copied().collect(),
+                cov: cov
+                    .row_iter()
+                    .map(|row| row.iter().copied().collect())
+                    .collect(),
+            })
+            .collect(),
+    )
+}
+
+fn regularized(cov: MNotes) -> MNotes {
+    cov + MNotes::identity() * REGULARIZATION
+}
+
+/// Refits the chord emission models to the samples in `samples_dir` as `components`-component
+/// mixtures, keeping `base` for any quality without enough samples.
+pub(crate) fn train(samples_dir: &Path, base: ModelParams, components: usize, output: &Path) {
+    let samples = Samples::load(samples_dir);
+    let mut params = base;
+    for (name, samples, mixture) in [
+        ("major", &samples.major, &mut params.major),
+        ("minor", &samples.minor, &mut params.minor),
+    ] {
+        match fit_mixture(samples, components) {
+            Some(fitted) => {
+                println!(
+                    "Fit {components}-component {name} mixture to {} samples",
+                    samples.len()
+                );
+                *mixture = fitted;
+            }
+            None => println!(
+                "Only {} {name} samples, keeping the base {name} model",
+                samples.len()
+            ),
+        }
+    }
+    params.save(output);
+}
+
+#[test]
+fn fit_matches_shipped_params() {
+    let samples = Samples::load(Path::new("data"));
+    let fitted = fit_gaussian(&samples.major).unwrap();
+    let shipped = &ModelParams::default().major[0];
+    for (a, b) in fitted.mean.iter().zip(&shipped.mean) {
+        assert!((a - b).abs() < 1e-4);
+    }
+    for (a, b) in fitted
+        .cov
+        .iter()
+        .flatten()
+        .zip(shipped.cov.iter().flatten())
+    {
+        assert!((a - b).abs() < 1e-4);
+    }
+}
+
+#[test]
+fn mixture_separates_voicings() {
+    // Two well separated clusters, e.g. open and barre voicings of the same chord.
+    let samples = (0..60u64)
+        .map(|i| {
+            (0..12)
+                .map(|j| {
+                    let centre = if j % 2 == i % 2 { 1.0 } else { 0.0 };
+                    let hash = (i * 12 + j).wrapping_mul(6364136223846793005) >> 40;
+                    centre + (hash % 1000) as f32 * 1e-4
+                })
+                .collect_vec()
+        })
+        .collect_vec();
+    let mixture = fit_mixture(&samples, 2).unwrap();
+    assert_eq!(mixture.len(), 2);
+    for component in &mixture {
+        assert!((component.weight - 0.5).abs() < 0.01);
+    }
+    assert!((mixture[0].mean[0] - mixture[1].mean[0]).abs() > 0.9);
+    assert!(fit_mixture(&samples[..20], 2).is_none());
+    // More components than clusters still fit, with weights that sum to one.
+    let mixture = fit_mixture(&samples, 3).unwrap();
+    let total: f32 = mixture.iter().map(|c| c.weight).sum();
+    assert!((total - 1.0).abs() < 1e-3);
+    assert!(mixture.iter().flat_map(|c| &c.mean).all(|m| m.is_finite()));
+}
@@ -0,0 +1,298 @@
+use std::collections::HashMap;
+
+use crate::midi::MidiMessage;
+
+const CHANNELS: usize = 16;
+const NOTES: usize = 128;
+const SUSTAIN: u8 = 64;
+const ALL_SOUND_OFF: u8 = 120;
+const ALL_NOTES_OFF: u8 = 123;
+
+/// Somewhere to send MIDI: the destination port, or a `Vec` in memory.
+pub(crate) trait Output {
+    fn send(&mut self, message: MidiMessage);
+    /// Sends a message that keeps its channel whatever the output's routing says, e.g. a harmony
+    /// voice on a channel of its own.
+    fn send_on_own_channel(&mut self, message: MidiMessage) {
+        self.send(message);
+    }
+    /// The channel a note sent on `channel` sounds on at the destination.
+    fn channel_for(&self, channel: u8, _own_channel: bool) -> u8 {
+        channel
+    }
+}
+impl Output for Vec<MidiMessage> {
+    fn send(&mut self, message: MidiMessage) {
+        self.push(message);
+    }
+}
+
+/// An output note sounded for a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Voice {
+    pub(crate) channel: u8,
+    pub(crate) note: u8,
+    /// Sent with `Output::send_on_own_channel`.
+    pub(crate) own_channel: bool,
+}
+impl Voice {
+    pub(crate) fn new(channel: u8, note: u8) -> Self {
+        Self {
+            channel,
+            note,
+            own_channel: false,
+        }
+    }
+}
+
+/// Tracks which output notes each held key sounds, reference counting output notes so that keys
+/// mapped to the same note don't cut each other off, wherever they are routed from. Whatever still
+/// sounds is released when the tracker is dropped.
+pub(crate) struct Voices<O: Output> {
+    pub(crate) output: O,
+    /// The voices sounded by each held key, by channel and note, lead first.
+    keys: HashMap<(u8, u8), Vec<Voice>>,
+    /// How many held keys sound each output note, by the channel it is routed to and note.
+    counts: [[u8; NOTES]; CHANNELS],
+}
+impl<O: Output> Voices<O> {
+    pub(crate) fn new(output: O) -> Self {
+        Self {
+            output,
+            keys: HashMap::new(),
+            counts: [[0; NOTES]; CHANNELS],
+        }
+    }
+    /// Sounds `mapped` for a key press, on the key's channel.
+    pub(crate) fn press(&mut self, channel: u8, note: u8, mapped: u8, velocity: u8) {
+        self.press_voices(channel, note, &[Voice::new(channel, mapped)], velocity);
+    }
+    /// Sounds several voices for a key press. A key pressed again without a release
+    /// first lets go of its previous notes, and a note that is already sounding is retriggered.
+    pub(crate) fn press_voices(&mut self, channel: u8, note: u8, voices: &[Voice], velocity: u8) {
+        self.release(channel, note, 0);
+        for &voice in voices {
+            *self.count(voice) += 1;
+            if *self.count(voice) > 1 {
+                self.send(voice, |channel, note| MidiMessage::NoteOff {
+                    channel,
+                    note,
+                    velocity: 0,
+                });
+            }
+            self.send(voice, |channel, note| MidiMessage::NoteOn {
+                channel,
+                note,
+                velocity,
+            });
+        }
+        self.keys.insert((channel, note), voices.to_vec());
+    }
+    fn count(&mut self, voice: Voice) -> &mut u8 {
+        let channel = self.output.channel_for(voice.channel, voice.own_channel);
+        &mut self.counts[channel as usize][voice.note as usize]
+    }
+    fn send(&mut self, voice: Voice, message: impl Fn(u8, u8) -> MidiMessage) {
+        let message = message(voice.channel, voice.note);
+        if voice.own_channel {
+            self.output.send_on_own_channel(message);
+        } else {
+            self.output.send(message);
+        }
+    }
+    /// Releases a key, silencing each of its notes once no other held key sounds it. Returns its
+    /// lead note, or `None` if the key wasn't sounding anything.
+    pub(crate) fn release(&mut self, channel: u8, note: u8, velocity: u8) -> Option<u8> {
+        let voices = self.keys.remove(&(channel, note))?;
+        for &voice in &voices {
+            *self.count(voice) -= 1;
+            if *self.count(voice) == 0 {
+                self.send(voice, |channel, note| MidiMessage::NoteOff {
+                    channel,
+                    note,
+                    velocity,
+                });
+            }
+        }
+        voices.first().map(|voice| voice.note)
+    }
+    /// The voices a held key sounds.
+    pub(crate) fn sounding(&self, channel: u8, note: u8) -> &[Voice] {
+        self.keys.get(&(channel, note)).map_or(&[], Vec::as_slice)
+    }
+    /// Releases every sounding note and forgets the held keys, e.g. before the mapping changes.
+    pub(crate) fn all_notes_off(&mut self) {
+        for c in 0..CHANNELS {
+            for m in 0..NOTES {
+                if self.counts[c][m] > 0 {
+                    // Already routed, so sent on the channel it sounds on.
+                    let voice = Voice {
+                        channel: c as u8,
+                        note: m as u8,
+                        own_channel: true,
+                    };
+                    self.send(voice, |channel, note| MidiMessage::NoteOff {
+                        channel,
+                        note,
+                        velocity: 0,
+                    });
+                }
+            }
+        }
+        self.keys.clear();
+        self.counts = [[0; NOTES]; CHANNELS];
+    }
+    /// Silences the destination on every channel, including notes that were never tracked.
+    pub(crate) fn panic(&mut self) {
+        self.all_notes_off();
+        for channel in 0..CHANNELS as u8 {
+            for controller in [SUSTAIN, ALL_SOUND_OFF, ALL_NOTES_OFF] {
+                self.output.send(MidiMessage::ControlChange {
+                    channel,
+                    controller,
+                    value: 0,
+                });
+            }
+        }
+    }
+}
+impl<O: Output> Drop for Voices<O> {
+    fn drop(&mut self) {
+        self.all_notes_off();
+    }
+}
+
+/// Messages as tests expect to see them sent, and the notes in what was sent.
+#[cfg(test)]
+pub(crate) mod messages {
+    use crate::midi::MidiMessage;
+
+    pub(crate) fn on(channel: u8, note: u8, velocity: u8) -> MidiMessage {
+        MidiMessage::NoteOn {
+            channel,
+            note,
+            velocity,
+        }
+    }
+    pub(crate) fn off(channel: u8, note: u8, velocity: u8) -> MidiMessage {
+        MidiMessage::NoteOff {
+            channel,
+            note,
+            velocity,
+        }
+    }
+    pub(crate) fn cc(channel: u8, controller: u8, value: u8) -> MidiMessage {
+        MidiMessage::ControlChange {
+            channel,
+            controller,
+            value,
+        }
+    }
+    /// The notes turned on, in order.
+    pub(crate) fn ons(messages: &[MidiMessage]) -> Vec<u8> {
+        messages
+            .iter()
+            .filter_map(|m| match *m {
+                MidiMessage::NoteOn { note, .. } => Some(note),
+                _ => None,
+            })
+            .collect()
+    }
+    /// The notes turned off, in order.
+    pub(crate) fn offs(messages: &[MidiMessage]) -> Vec<u8> {
+        messages
+            .iter()
+            .filter_map(|m| match *m {
+                MidiMessage::NoteOff { note, .. } => Some(note),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn shared_notes_are_not_cut_off() {
+    let on = |note| messages::on(0, note, 100);
+    let off = |note, velocity| messages::off(0, note, velocity);
+    let mut voices = Voices::new(vec![]);
+    // 61 and 60 both snap to 60: releasing one keeps it sounding, the note is retriggered.
+    voices.press(0, 60, 60, 100);
+    voices.press(0, 61, 60, 100);
+    assert_eq!(voices.release(0, 61, 64), Some(60));
+    assert_eq!(voices.output, vec![on(60), off(60, 0), on(60)]);
+    assert_eq!(voices.release(0, 60, 64), Some(60));
+    assert_eq!(voices.output.pop(), Some(off(60, 64)));
+    assert_eq!(voices.release(0, 60, 64), None);
+
+    // Pressing a key again without a release lets go of its old mapping.
+    voices.output.clear();
+    voices.press(0, 64, 64, 100);
+    voices.press(0, 64, 65, 100);
+    assert_eq!(voices.output, vec![on(64), off(64, 0), on(65)]);
+    assert_eq!(voices.sounding(0, 64), [Voice::new(0, 65)]);
+
+    // All notes off, here for 65 and 50, then the stale release sends nothing.
+    voices.output.clear();
+    voices.press(3, 50, 50, 100);
+    voices.all_notes_off();
+    assert_eq!(voices.release(3, 50, 0), None);
+    assert_eq!(voices.output[1..], [off(65, 0), messages::off(3, 50, 0)]);
+
+    voices.output.clear();
+    voices.press(0, 40, 40, 100);
+    voices.panic();
+    assert_eq!(voices.output[1], off(40, 0));
+    assert_eq!(voices.output.len(), 2 + 3 * CHANNELS);
+    assert!(voices.sounding(0, 40).is_empty());
+
+    // A harmonized key sounds on two channels, and shares its third with a plain key.
+    voices.output.clear();
+    voices.press(1, 64, 64, 90);
+    voices.press_voices(0, 60, &[Voice::new(0, 60), Voice::new(1, 64)], 100);
+    assert_eq!(voices.release(1, 64, 0), Some(64));
+    assert_eq!(voices.output.len(), 4);
+    assert_eq!(voices.release(0, 60, 0), Some(60));
+    assert_eq!(voices.output.len(), 6);
+    voices.all_notes_off();
+    assert_eq!(voices.counts, [[0; NOTES]; CHANNELS]);
+}
+
+#[test]
+fn counts_notes_where_they_are_routed() {
+    use crate::routing::Routing;
+    struct Routed(Routing, Vec<MidiMessage>);
+    impl Output for Routed {
+        fn send(&mut self, message: MidiMessage) {
+            self.1.extend(self.0.route(message, false));
+        }
+        fn send_on_own_channel(&mut self, message: MidiMessage) {
+            self.1.extend(self.0.route(message, true));
+        }
+        fn channel_for(&self, channel: u8, own_channel: bool) -> u8 {
+            self.0.channel_for(channel, own_channel)
+        }
+    }
+    let routing = Routing {
+        channel: Some(0),
+        ..Routing::default()
+    };
+    let mut voices = Voices::new(Routed(routing, vec![]));
+    // Keys on two channels merged onto one: the first release keeps the note sounding.
+    voices.press(0, 60, 60, 100);
+    voices.press(1, 60, 60, 100);
+    voices.release(0, 60, 0);
+    assert_eq!(voices.output.1.len(), 3);
+    voices.release(1, 60, 0);
+    assert_eq!(voices.output.1.len(), 4);
+    // A harmony voice on its own channel shares the note a key played there routes to.
+    let harmony = Voice {
+        own_channel: true,
+        ..Voice::new(0, 64)
+    };
+    voices.press_voices(2, 60, &[Voice::new(2, 60), harmony], 100);
+    voices.press(3, 64, 64, 90);
+    voices.release(2, 60, 0);
+    assert_eq!(voices.output.1.last(), Some(&messages::off(0, 60, 0)));
+    voices.all_notes_off();
+    assert_eq!(voices.output.1.last(), Some(&messages::off(0, 64, 0)));
+}
@@ -0,0 +1,143 @@
+use clap::ValueEnum;
+
+use crate::mapping::MAX_NOTE;
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum VoicingStyle {
+    /// Every tone within an octave, with the bottom one doubled on top for triads.
+    Close,
+    /// A close voicing with the second voice from the top dropped an octave.
+    Drop2,
+    /// The root in the bass and the chord stacked from an octave above it.
+    Spread,
+    /// The root, with the third and any seventh above it.
+    Shell,
+}
+
+/// Which chord a key plays in `SoloMode::ChordPad`.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PadChord {
+    /// The detected chord, whichever key is pressed.
+    Detected,
+    /// The triad on the scale degree under the key, picked as in `SoloMode::Nearest`.
+    Degree,
+}
+
+/// Voices chords for the chord pad, each moving as little as it can from the last.
+pub(crate) struct Voicer {
+    pub(crate) style: VoicingStyle,
+    previous: Option<Vec<u8>>,
+}
+impl Voicer {
+    pub(crate) fn new(style: VoicingStyle) -> Self {
+        Self {
+            style,
+            previous: None,
+        }
+    }
+    /// A voicing of `tones`, pitch classes from the root up, built in the octave of `key`.
+    pub(crate) fn voice(&mut self, key: u8, tones: &[u8]) -> Vec<u8> {
+        let key = key as i16;
+        let voicing = candidates(self.style, key / 12 * 12, tones)
+            .into_iter()
+            .filter(|v| v.iter().all(|n| (0..=MAX_NOTE).contains(n)))
+            .min_by_key(|v| {
+                let movement = self.previous.as_ref().map_or(0, |p| movement(p, v));
+                (movement, (v[0] - key).abs())
+            })
+            .map_or_else(|| vec![key as u8], |v| v.iter().map(|&n| n as u8).collect());
+        self.previous = Some(voicing.clone());
+        voicing
+    }
+}
+
+/// The voicings of `tones` in `style` built up from `low`, one for each way of ordering them.
+fn candidates(style: VoicingStyle, low: i16, tones: &[u8]) -> Vec<Vec<i16>> {
+    let tones = tones.iter().map(|&t| t as i16).collect::<Vec<_>>();
+    let inversions = (0..tones.len()).map(|r| {
+        let mut order = tones.clone();
+        order.rotate_left(r);
+        order
+    });
+    let root = above(low, tones[0]);
+    match style {
+        VoicingStyle::Close | VoicingStyle::Drop2 => inversions
+            .map(|order| {
+                let mut voicing = stack(low, &order);
+                if voicing.len() < 4 {
+                    voicing.push(voicing[0] + 12);
+                }
+                if style == VoicingStyle::Drop2 {
+                    let second = voicing.len() - 2;
+                    voicing[second] -= 12;
+                    voicing.sort_unstable();
+                }
+                voicing
+            })
+            .collect(),
+        VoicingStyle::Spread => inversions
+            .map(|order| [vec![root], stack(root + 12, &order)].concat())
+            .collect(),
+        VoicingStyle::Shell => {
+            let guides = tones.iter().skip(1).step_by(2).map(|&t| above(root + 1, t));
+            // Each guide tone close to the root or an octave higher.
+            guides.fold(vec![vec![root]], |voicings, guide| {
+                voicings
+                    .iter()
+                    .flat_map(|v| [0, 12].map(|octave| [v.clone(), vec![guide + octave]].concat()))
+                    .map(|mut v| {
+                        v.sort_unstable();
+                        v
+                    })
+                    .collect()
+            })
+        }
+    }
+}
+
+/// The lowest note from `floor` up with pitch class `tone`.
+fn above(floor: i16, tone: i16) -> i16 {
+    floor + (tone - floor).rem_euclid(12)
+}
+
+/// `order` stacked upwards from `low`, each tone the first above the last.
+fn stack(low: i16, order: &[i16]) -> Vec<i16> {
+    let mut floor = low;
+    order
+        .iter()
+        .map(|&tone| {
+            let note = above(floor, tone);
+            floor = note + 1;
+            note
+        })
+        .collect()
+}
+
+/// How far the notes of one voicing are from the nearest of the other, both ways round, so that
+/// voicings of different sizes compare.
+fn movement(from: &[u8], to: &[i16]) -> i16 {
+    let from = from.iter().map(|&n| n as i16).collect::<Vec<_>>();
+    let nearest = |note: i16, notes: &[i16]| notes.iter().map(|n| (n - note).abs()).min().unwrap();
+    to.iter().map(|&n| nearest(n, &from)).sum::<i16>()
+        + from.iter().map(|&n| nearest(n, to)).sum::<i16>()
+}
+
+#[test]
+fn voices_lead_smoothly() {
+    let (c, f, g) = ([0, 4, 7], [5, 9, 0], [7, 11, 2]);
+    let mut voicer = Voicer::new(VoicingStyle::Close);
+    // Root position to start, then the inversions nearest to it.
+    assert_eq!(voicer.voice(60, &c), [60, 64, 67, 72]);
+    assert_eq!(voicer.voice(60, &f), [60, 65, 69, 72]);
+    assert_eq!(voicer.voice(60, &g), [62, 67, 71, 74]);
+    assert_eq!(voicer.voice(60, &c), [60, 64, 67, 72]);
+
+    let first = |style, tones: &[u8]| Voicer::new(style).voice(60, tones);
+    assert_eq!(first(VoicingStyle::Drop2, &c), [60, 64, 67, 76]);
+    assert_eq!(first(VoicingStyle::Spread, &c), [60, 72, 76, 79]);
+    assert_eq!(first(VoicingStyle::Shell, &c), [60, 64]);
+    // A seventh chord's shell has its third and seventh.
+    assert_eq!(first(VoicingStyle::Shell, &[7, 11, 2, 5]), [67, 71, 77]);
+    // Nothing fits above G9, so the key is played alone.
+    assert_eq!(Voicer::new(VoicingStyle::Spread).voice(127, &g), [127]);
+}
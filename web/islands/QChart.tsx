@@ -47,18 +47,54 @@ function noteToString({ letter, accidental }: Note): string {
   }
   return [letterToString(letter), accidentalToString(accidental)].join("");
 }
-type SoloMode = "Chord" | "Nearest" | "Transpose";
+type SoloMode =
+  | "Chord"
+  | "Nearest"
+  | "Transpose"
+  | "Arpeggio"
+  | "Harmonize"
+  | "ChordPad";
+type ArpPattern = "Up" | "Down" | "UpDown" | "Random" | "AsPlayed";
+type Preset = "Pop" | "Blues" | "Jazz" | "Modal" | "Punk";
 interface Note {
   letter: Letter;
   accidental?: Accidental;
 }
-type WebInEvent = ({ type: "InferenceEvent" } & Payload) | { type: "Beat" } | {
-  type: "MidiEvent";
-  note: number;
-  mapped_note: number;
-  on: boolean;
-};
-type WebOutEvent = { SoloMode: SoloMode };
+type WebInEvent =
+  | ({ type: "InferenceEvent" } & Payload)
+  | ({ type: "FollowEvent" } & FollowPayload)
+  | ({ type: "LockState" } & Locks)
+  | ({ type: "KeyChange" } & KeyChange)
+  | { type: "Beat" }
+  | { type: "SoloScales"; names: string[] }
+  | { type: "CaptureError"; message: string }
+  | { type: "LockError"; message: string }
+  | {
+    type: "MidiEvent";
+    note: number;
+    mapped_note: number;
+    on: boolean;
+  };
+type WebOutEvent =
+  | { SoloMode: SoloMode }
+  | { Capture: string | null }
+  | "ResetAdaptation"
+  | { Preset: Preset | null }
+  | { LockScale: string | null }
+  | { FreezeChord: boolean }
+  | { ForceChord: string | null }
+  | { LearnTrigger: Trigger | null }
+  | "Panic"
+  | { SoloScale: string | null }
+  | { ArpPattern: ArpPattern };
+type Trigger = "LockScale" | "FreezeChord" | { ForceChord: string };
+type MidiControl = { Note: number } | { Controller: number };
+interface Locks {
+  scale: ModalKey | null;
+  chord: Chord | null;
+  learning: Trigger | null;
+  bindings: [MidiControl, Trigger][];
+}
 enum Flavor {
   "Major",
   "Minor",
@@ -70,13 +106,68 @@ interface Chord {
 type ChordInference = {
   y: number[];
   chord: Chord;
+  confidence: number;
+};
+type ChordProbability = {
+  chord: Chord;
+  probability: number;
+};
+type ScaleMode =
+  | "Ionian"
+  | "Dorian"
+  | "Phrygian"
+  | "Lydian"
+  | "Mixolydian"
+  | "Aeolian"
+  | "HarmonicMinor"
+  | "MelodicMinor";
+type ModalKey = {
+  root: Note;
+  mode: ScaleMode;
 };
 type Scale = {
+  key: ModalKey;
+  candidates: { key: ModalKey; score: number }[];
+};
+type Modulation =
+  | { Transposition: { semitones: number } }
+  | "Relative"
+  | "Parallel"
+  | "Other";
+type KeyRegion = {
+  key: ModalKey;
+  start: number;
+  end: number;
+  start_seconds: number;
+  end_seconds: number;
+};
+interface KeyChange {
+  from: ModalKey;
+  to: ModalKey;
+  beat: number;
+  seconds: number;
+  modulation: Modulation;
+  regions: KeyRegion[];
+}
+type Key = {
   root: Note;
   mode: "Major" | "Minor";
 };
+interface FollowPayload {
+  bar: number;
+  beat: number;
+  confidence: number;
+  chord: Chord;
+  upcoming: Chord;
+  next_chord: Chord;
+  beats_to_next: number;
+}
 interface Payload {
   scale: Scale;
+  key: Key;
+  key_confidence: number;
+  confidence: number;
+  alternatives: ChordProbability[];
   chord: Chord;
   chord_inferences: ChordInference[];
 }
@@ -87,13 +178,41 @@ export default function QChart() {
   const [mode, setMode] = useState<SoloMode>("Chord");
   const [mappedNotes, setMappedNotes] = useState<number[]>([]);
   const [ws, setWs] = useState<WebSocketClient>();
-  const [{ chord, chord_inferences, scale }, setChordInferences] = useState<
-    Payload
-  >({
-    chord: { chord_type: Flavor.Major, root: { letter: "C" } },
-    chord_inferences: [],
-    scale: { root: { letter: "C" }, mode: "Major" },
-  });
+  const [capture, setCapture] = useState<string>("");
+  const [capturing, setCapturing] = useState<boolean>(false);
+  const [captureError, setCaptureError] = useState<string | null>(null);
+  const [follow, setFollow] = useState<FollowPayload | null>(null);
+  const [locks, setLocks] = useState<Locks | null>(null);
+  const [keyChange, setKeyChange] = useState<KeyChange | null>(null);
+  const [soloScales, setSoloScales] = useState<string[]>([]);
+  const [lockLabel, setLockLabel] = useState<string>("");
+  const [lockError, setLockError] = useState<string | null>(null);
+  const [
+    {
+      chord,
+      chord_inferences,
+      scale,
+      key,
+      key_confidence,
+      confidence,
+      alternatives,
+    },
+    setChordInferences,
+  ] =
+    useState<
+      Payload
+    >({
+      chord: { chord_type: Flavor.Major, root: { letter: "C" } },
+      chord_inferences: [],
+      scale: {
+        key: { root: { letter: "C" }, mode: "Ionian" },
+        candidates: [],
+      },
+      key: { root: { letter: "C" }, mode: "Major" },
+      key_confidence: 0,
+      confidence: 0,
+      alternatives: [],
+    });
   useEffect(() => {
     const ws: WebSocketClient = new StandardWebSocketClient(endpoint);
     ws.on("message", (m) => {
@@ -115,6 +234,25 @@ export default function QChart() {
               break;
           }
           return;
+        case "FollowEvent":
+          setFollow(event);
+          return;
+        case "LockState":
+          setLocks(event);
+          return;
+        case "KeyChange":
+          setKeyChange(event);
+          return;
+        case "SoloScales":
+          setSoloScales(event.names);
+          return;
+        case "CaptureError":
+          setCapturing(false);
+          setCaptureError(event.message);
+          return;
+        case "LockError":
+          setLockError(event.message);
+          return;
         case "InferenceEvent":
           setChordInferences(event);
       }
@@ -147,19 +285,79 @@ export default function QChart() {
           {beat && <span class="flex w-3 h-3 bg-red-500 rounded-full"></span>}
         </div>
         <div class="flex flex-col items-center rounded shadow m-2 p-2">
-          <div class="text-xs">Scale</div>
+          <div class="text-xs" title="Key decoded with the chords">
+            Key (chords)
+          </div>
           <div class="font-bold">
-            {noteToString(scale.root)}
+            {noteToString(key.root)}
+            {key.mode === "Minor" ? "m" : ""}
           </div>
+          <div class="text-xs">{Math.round(key_confidence * 100)}%</div>
         </div>
+        <div class="flex flex-col items-center rounded shadow m-2 p-2">
+          <div class="text-xs" title="Key estimate the solo remapper uses">
+            Scale (remapping)
+          </div>
+          <div class="font-bold">
+            {noteToString(scale.key.root)} {scale.key.mode}
+          </div>
+          {scale.candidates.map((candidate) => (
+            <div class="text-xs text-gray-500">
+              {noteToString(candidate.key.root)} {candidate.key.mode}{" "}
+              {candidate.score.toFixed(2)}
+            </div>
+          ))}
+        </div>
+        {keyChange && (
+          <div class="flex flex-col rounded shadow m-2 p-2 text-xs">
+            <div>
+              {modulationString(keyChange.modulation)} at{" "}
+              {keyChange.seconds.toFixed(1)}s (beat {keyChange.beat})
+            </div>
+            {keyChange.regions.map(({ key, start_seconds, end_seconds }) => (
+              <div class="text-gray-500">
+                {start_seconds.toFixed(1)}–{end_seconds.toFixed(1)}s{" "}
+                {noteToString(key.root)} {key.mode}
+              </div>
+            ))}
+          </div>
+        )}
         <div class="flex flex-col items-center rounded shadow m-2 p-2">
           <div class="text-xs">Chord</div>
           <div class="font-bold">
             {chordString(chord)}
           </div>
+          <div class="text-xs">{Math.round(confidence * 100)}%</div>
+        </div>
+        {follow && (
+          <div class="flex flex-col items-center rounded shadow m-2 p-2">
+            <div class="text-xs">
+              Bar {follow.bar + 1}.{follow.beat + 1}{" "}
+              {Math.round(follow.confidence * 100)}%
+            </div>
+            <div class="font-bold">{chordString(follow.chord)}</div>
+            <div class="text-xs">
+              next beat {chordString(follow.upcoming)},{" "}
+              {chordString(follow.next_chord)} in {follow.beats_to_next}
+            </div>
+          </div>
+        )}
+        <div class="flex flex-col rounded shadow m-2 p-2 text-xs text-gray-500">
+          {alternatives.map(({ chord, probability }) => (
+            <div>
+              {chordString(chord)} {Math.round(probability * 100)}%
+            </div>
+          ))}
         </div>
         <ul class="mx-2 items-center text-sm font-medium text-gray-900 bg-white border border-gray-200 rounded-lg sm:flex dark:bg-gray-700 dark:border-gray-600 dark:text-white">
-          {(["Chord", "Nearest", "Transpose"] as SoloMode[]).map((thisMode) => (
+          {([
+            "Chord",
+            "Nearest",
+            "Transpose",
+            "Arpeggio",
+            "Harmonize",
+            "ChordPad",
+          ] as SoloMode[]).map((thisMode) => (
             <li class="px-2 border-b border-gray-200 sm:border-b-0 sm:border-r dark:border-gray-600">
               <div class="flex items-center pl-3">
                 <input
@@ -185,6 +383,165 @@ export default function QChart() {
             </li>
           ))}
         </ul>
+        {mode === "Arpeggio" && (
+          <select
+            class="rounded shadow m-2 p-2 text-sm"
+            onChange={(e) => {
+              const message: WebOutEvent = {
+                ArpPattern: e.currentTarget.value as ArpPattern,
+              };
+              ws?.send(JSON.stringify(message));
+            }}
+          >
+            {(["Up", "Down", "UpDown", "Random", "AsPlayed"] as ArpPattern[])
+              .map((pattern) => <option value={pattern}>{pattern}</option>)}
+          </select>
+        )}
+        <div class="flex items-center rounded shadow m-2 p-2 text-sm">
+          <input
+            class="w-16 mr-2 border border-gray-300 rounded px-1"
+            placeholder="Am"
+            value={capture}
+            disabled={capturing}
+            onInput={(e) => setCapture(e.currentTarget.value)}
+          />
+          <button
+            class="px-2 rounded bg-gray-200"
+            onClick={() => {
+              const message: WebOutEvent = {
+                Capture: capturing ? null : capture,
+              };
+              ws?.send(JSON.stringify(message));
+              setCapturing(!capturing);
+              setCaptureError(null);
+            }}
+          >
+            {capturing ? "Stop capture" : "Capture"}
+          </button>
+          {captureError && (
+            <span class="ml-2 text-xs text-red-500">{captureError}</span>
+          )}
+        </div>
+        <select
+          class="rounded shadow m-2 p-2 text-sm"
+          onChange={(e) => {
+            const value = e.currentTarget.value;
+            const message: WebOutEvent = {
+              SoloScale: value === "" ? null : value,
+            };
+            ws?.send(JSON.stringify(message));
+          }}
+        >
+          <option value="">Scale of the key</option>
+          {soloScales.map((scale) => <option value={scale}>{scale}</option>)}
+        </select>
+        <select
+          class="rounded shadow m-2 p-2 text-sm"
+          onChange={(e) => {
+            const value = e.currentTarget.value;
+            const message: WebOutEvent = {
+              Preset: value === "" ? null : value as Preset,
+            };
+            ws?.send(JSON.stringify(message));
+          }}
+        >
+          <option value="">Model transitions</option>
+          {(["Pop", "Blues", "Jazz", "Modal", "Punk"] as Preset[]).map((preset) => (
+            <option value={preset}>{preset}</option>
+          ))}
+        </select>
+        <div class="flex flex-col rounded shadow m-2 p-2 text-sm">
+          <div class="flex items-center">
+            <input
+              class="w-16 mr-2 border border-gray-300 rounded px-1"
+              placeholder="Am / D dorian"
+              value={lockLabel}
+              onInput={(e) => setLockLabel(e.currentTarget.value)}
+            />
+            {(
+              [
+                ["Lock scale", { LockScale: lockLabel }],
+                ["Force chord", { ForceChord: lockLabel }],
+                ["Freeze", { FreezeChord: true }],
+                ["Release", { ForceChord: null }],
+                ["Unlock scale", { LockScale: null }],
+              ] as [string, WebOutEvent][]
+            ).map(([label, message]) => (
+              <button
+                class="px-2 mr-1 rounded bg-gray-200"
+                onClick={() => {
+                  ws?.send(JSON.stringify(message));
+                  setLockError(null);
+                }}
+              >
+                {label}
+              </button>
+            ))}
+          </div>
+          {lockError && (
+            <div class="mt-1 text-xs text-red-500">{lockError}</div>
+          )}
+          <div class="flex items-center mt-1 text-xs">
+            Learn MIDI:
+            {(
+              [
+                ["scale", "LockScale"],
+                ["freeze", "FreezeChord"],
+                ["force", { ForceChord: lockLabel }],
+              ] as [string, Trigger][]
+            ).map(([label, trigger]) => (
+              <button
+                class="px-2 ml-1 rounded bg-gray-200"
+                onClick={() => {
+                  const message: WebOutEvent = { LearnTrigger: trigger };
+                  ws?.send(JSON.stringify(message));
+                }}
+              >
+                {label}
+              </button>
+            ))}
+          </div>
+          {locks && (
+            <div class="mt-1 text-xs text-gray-500">
+              {locks.scale && (
+                <div>
+                  Scale locked to {noteToString(locks.scale.root)}{" "}
+                  {locks.scale.mode}
+                </div>
+              )}
+              {locks.chord && <div>Chord locked to {chordString(locks.chord)}</div>}
+              {locks.learning && <div>Waiting for a MIDI note or controller…</div>}
+              {locks.bindings.map(([control, trigger]) => (
+                <div>
+                  {"Note" in control
+                    ? `note ${control.Note}`
+                    : `CC ${control.Controller}`}{" "}
+                  → {typeof trigger === "string"
+                    ? trigger
+                    : `ForceChord ${trigger.ForceChord}`}
+                </div>
+              ))}
+            </div>
+          )}
+        </div>
+        <button
+          class="rounded shadow m-2 p-2 text-sm"
+          onClick={() => {
+            const message: WebOutEvent = "ResetAdaptation";
+            ws?.send(JSON.stringify(message));
+          }}
+        >
+          New song
+        </button>
+        <button
+          class="rounded shadow m-2 p-2 text-sm bg-red-100"
+          onClick={() => {
+            const message: WebOutEvent = "Panic";
+            ws?.send(JSON.stringify(message));
+          }}
+        >
+          Panic
+        </button>
       </div>
 
       <TimelineComponent timeline={timeline} />
@@ -203,7 +560,7 @@ export default function QChart() {
         }}
       >
         {sliced.map((
-          { y, chord },
+          { y, chord, confidence },
           i,
         ) => (
           <div
@@ -217,7 +574,9 @@ export default function QChart() {
                 {positionalNotes[j]}
               </div>
             ))}
-            <div class="font-bold">{chordString(chord)}</div>
+            <div class="font-bold" style={{ opacity: 0.3 + 0.7 * confidence }}>
+              {chordString(chord)}
+            </div>
           </div>
         ))}
       </div>
@@ -257,6 +616,14 @@ function TimelineComponent({ timeline }: { timeline: Timeline }) {
   );
 }
 
+function modulationString(modulation: Modulation): string {
+  if (typeof modulation === "string") {
+    return modulation;
+  }
+  const { semitones } = modulation.Transposition;
+  return `Up ${semitones} semitone${Math.abs(semitones) === 1 ? "" : "s"}`
+    .replace("Up -", "Down ");
+}
 function chordString(
   chord: Chord,
 ): import("https://esm.sh/v113/preact@10.11.0/src/index").ComponentChildren {